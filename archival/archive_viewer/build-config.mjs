@@ -32,8 +32,10 @@ export const buildOptions = {
                 <title>Archive viewer</title>
                 <script>
                     window.jsonp_parse = (data) => window.jsonData = data;
+                    window.jsonp_metadata = (data) => window.archiveMetadata = data;
                 </script>
                 <script type="text/javascript" src="messages.jsonp"></script>
+                <script type="text/javascript" src="metadata.jsonp"></script>
             </head>
             <body>
 <!--                <object data="messages.json" style="display: none;" onload="this.before(this.contentDocument.children[0]); this.remove();"></object>-->
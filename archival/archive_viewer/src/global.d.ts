@@ -21,5 +21,6 @@ declare global {
 
   interface Window {
     jsonData?: string;
+    archiveMetadata?: unknown;
   }
 }
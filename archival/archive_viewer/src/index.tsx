@@ -1,4 +1,18 @@
-import { Attachment, Message, StickerItem } from './types.v1';
+import {
+  ArchiveMetadata,
+  Attachment,
+  AttachmentsProcessed,
+  ChannelSnapshot,
+  ComponentProcessed,
+  Message,
+  PollProcessed,
+  ReferencedMessage,
+  SnapshotProcessed,
+  StickerItem,
+  StickerProcessed,
+  ThumbnailProcessed,
+  VoiceProcessed,
+} from './types.v1';
 import { createEl } from 'janadom';
 import './reset.css';
 import './index.css';
@@ -23,7 +37,10 @@ Marked.setOptions({
   // },
 });
 
-function parseContent(content: string, message: Message): HTMLElement[] {
+function parseContent(
+  content: string,
+  message: Message | ReferencedMessage,
+): HTMLElement[] {
   let element = <div></div>;
   let parsed = Marked.parse(content);
   // console.log(parsed);
@@ -43,7 +60,7 @@ function parseContent(content: string, message: Message): HTMLElement[] {
 
   parsed = parsed.replace(/&lt;@&amp;(\d{18})&gt;/g, (text, id) => {
     // console.log(text, id);
-    let mentioned = message['mention_roles::processed'].find(
+    let mentioned = message['mention_roles::processed']?.find(
       (e) => e.id === id,
     );
     if (!mentioned) return text;
@@ -53,7 +70,7 @@ function parseContent(content: string, message: Message): HTMLElement[] {
 
   parsed = parsed.replace(/&lt;#(\d{18})&gt;/g, (text, id) => {
     // console.log(text, id);
-    let mentioned = message['mention_channels::processed'][id];
+    let mentioned = message['mention_channels::processed']?.[id];
     if (!mentioned) return text;
     return `<span class='mention'>#${mentioned}</span>`;
   });
@@ -63,14 +80,90 @@ function parseContent(content: string, message: Message): HTMLElement[] {
   return [...(element.children as unknown as HTMLElement[])];
 }
 
-function attachment(attachment: Attachment): HTMLElement {
+function voiceMessage(attachment: Attachment, voice: VoiceProcessed) {
+  let max = Math.max(1, ...voice.waveform);
+  return (
+    <div class='attachment voice'>
+      <div class='flex-row waveform'>
+        {voice.waveform.map((e) => (
+          <div class='waveform-bar' style={`height: ${(e / max) * 100}%`}></div>
+        ))}
+      </div>
+      <audio controls src={attachment.url}></audio>
+      {voice.duration_secs !== undefined ? (
+        <div class='attachment-size'>{voice.duration_secs.toFixed(1)}s</div>
+      ) : null}
+    </div>
+  );
+}
+
+function attachment(
+  attachment: Attachment,
+  processed: AttachmentsProcessed,
+): HTMLElement {
+  let byId = <T extends { attachment: string }>(list: T[] | undefined) =>
+    list?.find((e) => e.attachment === attachment.id);
+  let element = attachmentBody(
+    attachment,
+    byId(processed['voice::processed']),
+    byId(processed['thumbnails::processed']),
+  );
+  let budgeted = byId(processed['budget::processed']);
+  if (!budgeted) return element;
+  let action =
+    budgeted.action === 'dropped'
+      ? 'Removed from the archive'
+      : 'Downscaled in the archive';
+  return (
+    <div>
+      {element}
+      <div class='attachment-size'>
+        {action}, original: {prettyBytes(budgeted.original_size)}
+        {budgeted.original_url ? (
+          <span>
+            {' at '}
+            <a class='budget-link' href={budgeted.original_url}>
+              {budgeted.original_url}
+            </a>
+          </span>
+        ) : null}
+      </div>
+    </div>
+  );
+}
+
+function attachmentBody(
+  attachment: Attachment,
+  voice: VoiceProcessed | undefined,
+  thumbnail: ThumbnailProcessed | undefined,
+): HTMLElement {
+  if (voice) return voiceMessage(attachment, voice);
+  if (thumbnail) {
+    // The full image only loads when opened
+    return (
+      <a href={attachment.url} target='_blank'>
+        <img
+          src={thumbnail.thumbnail}
+          alt={attachment.filename}
+          title={`${thumbnail.width}×${thumbnail.height}`}
+          loading='lazy'
+          class='image'
+        ></img>
+      </a>
+    );
+  }
   let extension = attachment.url.toLowerCase().split('.').pop()!;
   if (
     attachment.content_type?.startsWith('image') ||
     extension.match(/png|jpg|jpeg|jfif|pjpeg|pjp|svg|gif|webp|apng|avif/)
   ) {
     return (
-      <img src={attachment.url} alt={attachment.filename} class='image'></img>
+      <img
+        src={attachment.url}
+        alt={attachment.filename}
+        loading='lazy'
+        class='image'
+      ></img>
     );
   } else {
     return (
@@ -85,22 +178,155 @@ function attachment(attachment: Attachment): HTMLElement {
   }
 }
 
-function sticker(message: Message, sticker: StickerItem): HTMLElement | null {
-  let stickerImage = message['stickers::processed'][sticker.id];
-  if (!stickerImage) return null;
-  let extension = stickerImage.toLowerCase().split('.').pop()!;
-  if (extension === 'json') {
+function sticker(
+  stickers: Record<string, StickerProcessed> | undefined,
+  sticker: StickerItem,
+): HTMLElement | null {
+  let stored = stickers?.[sticker.id];
+  if (!stored) return null;
+  let title = stored.pack ? `${stored.name} (${stored.pack})` : stored.name;
+  if (stored.lottie) {
+    let { width, height, frame_rate, in_point, out_point } = stored.lottie;
+    let details = ['Lottie animation'];
+    if (width && height) details.push(`${width}x${height}`);
+    if (frame_rate && out_point !== undefined) {
+      let seconds = (out_point - (in_point ?? 0)) / frame_rate;
+      details.push(`${seconds.toFixed(1)}s`);
+    }
     return (
-      <div class='attachment'>
-        :{sticker.name}:{' '}
-        <span class='attachment-size'>(unsupported animated sticker)</span>
-      </div>
+      <a class='attachment' href={stored.path} title={stored.description ?? ''}>
+        :{title}:{' '}
+        <span class='attachment-size'>({details.join(', ')})</span>
+      </a>
     );
   } else {
-    return <img class='sticker' src={stickerImage} alt={sticker.name} />;
+    return (
+      <img
+        class='sticker'
+        src={stored.path}
+        alt={stored.name}
+        title={stored.description ? `${title}: ${stored.description}` : title}
+      />
+    );
   }
 }
 
+function poll(poll: PollProcessed): HTMLElement {
+  let total = poll.total_votes ?? 0;
+  let status = poll.finalized
+    ? 'Final results'
+    : poll.expiry
+      ? `Ends ${new Date(poll.expiry).toUTCString()}`
+      : '';
+  return (
+    <div class='attachment poll'>
+      <div class='poll-question'>{poll.question ?? ''}</div>
+      {poll.answers.map((answer) => {
+        let percent = total > 0 ? ((answer.votes ?? 0) / total) * 100 : 0;
+        return (
+          <div class='poll-answer'>
+            <div
+              class='poll-answer-fill'
+              style={`width: ${percent.toFixed(1)}%`}
+            ></div>
+            <span class='poll-answer-text'>
+              {answer.emoji ? (
+                <img class='inline-emoji' src={answer.emoji} alt='emoji' />
+              ) : null}{' '}
+              {answer.text ?? ''}
+            </span>
+            <span class='attachment-size'>
+              {answer.votes !== undefined
+                ? `${answer.votes} (${percent.toFixed(0)}%)`
+                : ''}
+            </span>
+          </div>
+        );
+      })}
+      <div class='attachment-size'>
+        {poll.total_votes !== undefined ? `${total} votes` : ''}
+        {poll.allow_multiselect ? ' • Multiple answers' : ''}
+        {status ? ` • ${status}` : ''}
+      </div>
+    </div>
+  );
+}
+
+function components(rows: ComponentProcessed[][] | undefined) {
+  if (!rows) return null;
+  return (
+    <div>
+      {rows.map((row) => (
+        <div class='flex-row component-row'>
+          {row.map((component) => {
+            switch (component.type) {
+              case 'button': {
+                let inner = (
+                  <span>
+                    {component.emoji ? (
+                      <img
+                        class='inline-emoji'
+                        src={component.emoji}
+                        alt='emoji'
+                      />
+                    ) : null}{' '}
+                    {component.label ?? ''}
+                  </span>
+                );
+                let cls = `button button-${component.style}`;
+                return component.url ? (
+                  <a class={cls} href={component.url}>
+                    {inner}
+                  </a>
+                ) : (
+                  <span class={cls}>{inner}</span>
+                );
+              }
+              case 'select':
+                return (
+                  <select class='select' disabled>
+                    <option>{component.placeholder ?? ''}</option>
+                    {component.options.map((e) => (
+                      <option>{e.label}</option>
+                    ))}
+                  </select>
+                );
+              case 'text_input':
+                return (
+                  <span class='attachment-size'>
+                    {component.label ?? ''}: {component.value ?? ''}
+                  </span>
+                );
+            }
+          })}
+        </div>
+      ))}
+    </div>
+  );
+}
+
+function forwarded(
+  snapshot: SnapshotProcessed,
+  message: Message,
+): HTMLElement {
+  return (
+    <div class='forwarded'>
+      <div class='time'>Forwarded</div>
+      <div class='content markdown'>
+        {parseContent(snapshot.content, { ...message, ...snapshot })}
+      </div>
+      {snapshot.attachments.map((e) =>
+        attachment(e, snapshot),
+      )}
+      {snapshot.sticker_items.map((e) =>
+        sticker(snapshot['stickers::processed'], e),
+      )}
+      {components(snapshot['components::processed'])}
+      <div class='time'>{new Date(snapshot.timestamp).toUTCString()}</div>
+    </div>
+  );
+}
+
 function highlight(targetId: string) {
   let element = document.getElementById(targetId);
   if (element) {
@@ -114,9 +340,10 @@ function highlight(targetId: string) {
 function reactions(message: Message) {
   return (
     <div class='flex-row'>
-      {message['reactions::processed'].map(({ count, path }) => {
+      {message['reactions::processed'].map(({ count, path, users }) => {
+        let title = users?.map((e) => e.global_name ?? e.name).join(', ');
         return (
-          <span class='reaction'>
+          <span class='reaction' title={title ?? ''}>
             <img src={path} alt='reaction' class='pic' /> {count}
           </span>
         );
@@ -132,9 +359,26 @@ function message(
 ): HTMLElement {
   let date = new Date(message.timestamp);
   let short_time = date.toTimeString().slice(0, 5);
+  let system = message['system::processed'];
+  if (system) {
+    return (
+      <div class={`message system system-${system.kind}`} id={message.id}>
+        <div class='flex-row'>
+          <div class='pfp-spacer time'>{short_time}</div>
+          <div class='body'>
+            <span class='system-text'>{system.text}</span>{' '}
+            <span class='time'>{date.toUTCString()}</span>
+            {reactions(message)}
+          </div>
+        </div>
+      </div>
+    );
+  }
   let show_header =
     previous?.author.id != message.author.id || message.referenced_message;
-  let replyTo = old[message.referenced_message?.id ?? ''];
+  // Messages outside the archived range are only available as an embedded copy
+  let replyTo: Message | ReferencedMessage | undefined =
+    old[message.referenced_message?.id ?? ''] ?? message.referenced_message;
   let reply: HTMLElement | null = null;
   if (replyTo) {
     let content =
@@ -191,8 +435,17 @@ function message(
           <div class='content markdown'>
             {parseContent(message.content, message)}
           </div>
-          {message.attachments.map(attachment)}
-          {message.sticker_items.map((e) => sticker(message, e))}
+          {message.attachments.map((e) =>
+            attachment(e, message),
+          )}
+          {message.sticker_items.map((e) =>
+            sticker(message['stickers::processed'], e),
+          )}
+          {message['snapshots::processed']?.map((e) => forwarded(e, message))}
+          {message['poll::processed']
+            ? poll(message['poll::processed'])
+            : null}
+          {components(message['components::processed'])}
           {reactions(message)}
         </div>
       </div>
@@ -200,6 +453,79 @@ function message(
   );
 }
 
+function overwriteName(overwrite: ChannelSnapshot['permission_overwrites'][0]) {
+  if ('role' in overwrite.target) {
+    return '@' + (overwrite.target.role.name ?? overwrite.target.role.id);
+  }
+  return overwrite.target.member.name ?? overwrite.target.member.id;
+}
+
+function channelHeader(channel: ChannelSnapshot): HTMLElement {
+  let location = [channel.category?.name, channel.parent?.name]
+    .filter((e) => e)
+    .join(' / ');
+  return (
+    <details class='channel-header'>
+      <summary>
+        <span class='username'>#{channel.name}</span>{' '}
+        {location ? <span class='time'>in {location}</span> : null}
+        {channel.nsfw ? <span class='time'> • NSFW</span> : null}
+        {channel.slowmode_secs ? (
+          <span class='time'> • Slowmode {channel.slowmode_secs}s</span>
+        ) : null}
+        {channel.topic ? (
+          <div class='attachment-size'>{channel.topic}</div>
+        ) : null}
+      </summary>
+      <div class='attachment-size'>
+        Created {new Date(channel.created_at).toUTCString()} •{' '}
+        {channel.pinned_messages === null
+          ? 'pinned messages unavailable'
+          : `${channel.pinned_messages.length} pinned messages`}
+      </div>
+      {channel.permission_overwrites.map((overwrite) => (
+        <div class='attachment-size'>
+          {overwriteName(overwrite)}:{' '}
+          {overwrite.allow_names.length
+            ? `allow ${overwrite.allow_names.join(', ')}`
+            : ''}
+          {overwrite.allow_names.length && overwrite.deny_names.length
+            ? '; '
+            : ''}
+          {overwrite.deny_names.length
+            ? `deny ${overwrite.deny_names.join(', ')}`
+            : ''}
+        </div>
+      ))}
+    </details>
+  );
+}
+
+function archiveHeader(metadata: ArchiveMetadata): HTMLElement {
+  let guild = metadata.guild;
+  return (
+    <div class='archive-header'>
+      {guild?.banner ? (
+        <img class='guild-banner' src={guild.banner} alt='banner' />
+      ) : null}
+      {guild ? (
+        <div class='flex-row'>
+          {guild.icon ? (
+            <img class='pfp' src={guild.icon} alt={guild.name} />
+          ) : null}
+          <div class='body'>
+            <div class='username'>{guild.name}</div>
+            <div class='time'>
+              Archived {new Date(metadata.snapshot_time).toUTCString()}
+            </div>
+          </div>
+        </div>
+      ) : null}
+      {metadata.channels.map(channelHeader)}
+    </div>
+  );
+}
+
 function showJson(data: unknown) {
   let messages = data as Message[];
   let old: Record<string, Message> = {};
@@ -210,6 +536,11 @@ function showJson(data: unknown) {
     processed_messages.push(message(current, previous, old));
     old[current.id] = current;
   }
+  if (window.archiveMetadata) {
+    document.body.appendChild(
+      archiveHeader(window.archiveMetadata as ArchiveMetadata),
+    );
+  }
   document.body.appendChild(<div>{processed_messages}</div>);
 }
 
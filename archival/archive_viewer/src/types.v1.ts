@@ -25,10 +25,17 @@ export interface Message {
   pinned: boolean;
   reactions: Reaction[];
   'mention_channels::processed': Record<string, string>;
-  'stickers::processed': Record<string, string>;
+  'stickers::processed': Record<string, StickerProcessed>;
   'mention_roles::processed': MentionRolesProcessed[];
   'author_avatar::processed'?: string;
   'reactions::processed': ReactionsProcessed[];
+  'poll::processed'?: PollProcessed;
+  'snapshots::processed'?: SnapshotProcessed[];
+  'voice::processed'?: VoiceProcessed[];
+  'components::processed'?: ComponentProcessed[][];
+  'system::processed'?: SystemProcessed;
+  'budget::processed'?: BudgetProcessed[];
+  'thumbnails::processed'?: ThumbnailProcessed[];
   referenced_message?: ReferencedMessage;
   sticker_items: StickerItem[];
   thread: unknown;
@@ -188,6 +195,15 @@ export interface Emoji {
 export interface ReactionsProcessed {
   count: number;
   path: string;
+  users?: ReactionUser[];
+}
+
+export interface ReactionUser {
+  id: string;
+  name: string;
+  global_name?: string;
+  bot: boolean;
+  avatar: string;
 }
 
 export interface ReferencedMessage {
@@ -215,6 +231,11 @@ export interface ReferencedMessage {
   pinned: boolean;
   reactions: unknown[];
   referenced_message: unknown;
+  'mention_channels::processed'?: Record<string, string>;
+  'stickers::processed'?: Record<string, StickerProcessed>;
+  'mention_roles::processed'?: MentionRolesProcessed[];
+  'author_avatar::processed'?: string;
+  'system::processed'?: SystemProcessed;
   sticker_items: StickerItem[];
   thread: unknown;
   timestamp: string;
@@ -228,3 +249,169 @@ export interface StickerItem {
   id: string;
   name: string;
 }
+
+export interface StickerProcessed {
+  path: string;
+  name: string;
+  format: number;
+  pack?: string;
+  description?: string;
+  lottie?: LottieInfo;
+}
+
+export interface LottieInfo {
+  version?: string;
+  width?: number;
+  height?: number;
+  frame_rate?: number;
+  in_point?: number;
+  out_point?: number;
+}
+
+export interface PollProcessed {
+  question?: string;
+  answers: PollAnswerProcessed[];
+  expiry?: string;
+  allow_multiselect: boolean;
+  finalized: boolean;
+  total_votes?: number;
+}
+
+export interface PollAnswerProcessed {
+  id: number;
+  text?: string;
+  emoji?: string;
+  votes?: number;
+}
+
+export interface SnapshotProcessed {
+  content: string;
+  timestamp: string;
+  edited_timestamp?: string;
+  mentions: Mention[];
+  attachments: Attachment[];
+  embeds: Embed[];
+  type: number;
+  sticker_items: StickerItem[];
+  'stickers::processed': Record<string, StickerProcessed>;
+  'voice::processed': VoiceProcessed[];
+  'components::processed': ComponentProcessed[][];
+  'budget::processed'?: BudgetProcessed[];
+  'thumbnails::processed'?: ThumbnailProcessed[];
+}
+
+/** Processed keys that describe the attachments of a message */
+export interface AttachmentsProcessed {
+  'voice::processed'?: VoiceProcessed[];
+  'budget::processed'?: BudgetProcessed[];
+  'thumbnails::processed'?: ThumbnailProcessed[];
+}
+
+export interface ThumbnailProcessed {
+  attachment: string;
+  thumbnail: string;
+  width: number;
+  height: number;
+}
+
+export interface BudgetProcessed {
+  attachment: string;
+  action: 'dropped' | 'downscaled';
+  original_url?: string;
+  original_size: number;
+}
+
+export interface VoiceProcessed {
+  attachment: string;
+  duration_secs?: number;
+  waveform: number[];
+}
+
+export type ComponentProcessed =
+  | ButtonProcessed
+  | SelectProcessed
+  | TextInputProcessed;
+
+export interface ButtonProcessed {
+  type: 'button';
+  label?: string;
+  style: string;
+  url?: string;
+  emoji?: string;
+  disabled: boolean;
+}
+
+export interface SelectProcessed {
+  type: 'select';
+  placeholder?: string;
+  options: SelectOptionProcessed[];
+  disabled: boolean;
+}
+
+export interface SelectOptionProcessed {
+  label: string;
+  description?: string;
+  emoji?: string;
+  default: boolean;
+}
+
+export interface TextInputProcessed {
+  type: 'text_input';
+  label?: string;
+  placeholder?: string;
+  value?: string;
+}
+
+export interface SystemProcessed {
+  kind: string;
+  text: string;
+}
+
+export interface ArchiveMetadata {
+  snapshot_time: string;
+  guild?: GuildSnapshot;
+  channels: ChannelSnapshot[];
+}
+
+export interface GuildSnapshot {
+  id: string;
+  name: string;
+  description?: string;
+  owner_id: string;
+  premium_tier: number;
+  approximate_member_count?: number;
+  icon?: string;
+  banner?: string;
+}
+
+export interface ChannelRef {
+  id: string;
+  name?: string;
+}
+
+export type OverwriteTarget =
+  | { role: { id: string; name?: string } }
+  | { member: { id: string; name?: string } };
+
+export interface OverwriteSnapshot {
+  target: OverwriteTarget;
+  allow: string;
+  deny: string;
+  allow_names: string[];
+  deny_names: string[];
+}
+
+export interface ChannelSnapshot {
+  id: string;
+  name: string;
+  kind: number;
+  topic?: string;
+  parent?: ChannelRef;
+  category?: ChannelRef;
+  nsfw: boolean;
+  slowmode_secs?: number;
+  position: number;
+  created_at: string;
+  permission_overwrites: OverwriteSnapshot[];
+  pinned_messages: string[] | null;
+}
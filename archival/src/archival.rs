@@ -1,24 +1,46 @@
+use crate::images::{process_images, ImageProcessing};
+use crate::redaction::{write_generated_avatar, RedactionOptions, Redactor};
 use anyhow::{anyhow, bail, Context, Result};
 use futures::Stream;
 use futures::StreamExt;
 use poise::serenity_prelude::{
-    ChannelId, EmojiId, Message, ReactionType, StickerId, StickerItem, Timestamp, User, UserId,
+    Attachment, AttachmentId, ChannelId, EmojiId, Message, ReactionType, Sticker,
+    StickerFormatType, StickerId, StickerItem, Timestamp, User, UserId,
 };
 use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::time::Duration;
 use tempfile::{tempdir, NamedTempFile, TempDir};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use twemoji_assets::png::PngTwemojiAsset;
+use utils::reporter::{CountingReporter, Reporter};
 use utils::web_files::download_to_file;
-use utils::zip::archive_directory;
+use utils::zip::{write_directory, ArchiveSettings};
+use wiper::wiping::ArchivedMessages;
+
+mod extras;
+mod metadata;
+
+/// Optional features of the archival process
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// Store the list of users for every reaction
+    pub reaction_users: bool,
+    /// Generate thumbnails and re-encode image attachments
+    pub images: Option<ImageProcessing>,
+    /// Container and compression of the resulting archive
+    pub archive: ArchiveSettings,
+    /// Pseudonymize users and redact text, for archives that are shared
+    pub redaction: Option<RedactionOptions>,
+}
 
 #[derive(Debug)]
 struct ArchivalState {
+    options: ArchiveOptions,
     time_range: Option<Range<Timestamp>>,
     root_dir: TempDir,
     assets_dir: PathBuf,
@@ -26,11 +48,14 @@ struct ArchivalState {
     processed_count: usize,
     avatars: FxHashMap<UserId, PathBuf>,
     emojis: FxHashMap<ReactionType, PathBuf>,
-    stickers: FxHashMap<StickerId, PathBuf>,
+    stickers: FxHashMap<StickerId, StickerStore>,
+    attachments: FxHashMap<AttachmentId, String>,
+    metadata: metadata::ArchiveMetadata,
+    redactor: Option<Redactor>,
 }
 
 impl ArchivalState {
-    async fn create() -> Result<Self> {
+    async fn create(options: ArchiveOptions) -> Result<Self> {
         let dir = tempdir()?;
         let file = File::create(dir.path().join("messages.jsonp")).await?;
         File::create(dir.path().join("archive.html"))
@@ -39,7 +64,9 @@ impl ArchivalState {
             .await?;
         let assets_dir = dir.path().join("assets");
         tokio::fs::create_dir(&assets_dir).await?;
+        let redactor = options.redaction.clone().map(Redactor::new).transpose()?;
         Ok(ArchivalState {
+            options,
             time_range: None,
             root_dir: dir,
             assets_dir,
@@ -48,11 +75,22 @@ impl ArchivalState {
             avatars: Default::default(),
             emojis: Default::default(),
             stickers: Default::default(),
+            attachments: Default::default(),
+            metadata: Default::default(),
+            redactor,
         })
     }
 
     async fn finalize(&mut self) -> Result<()> {
         self.file.write_all("\n])".as_bytes()).await?;
+        let metadata = format!(
+            "jsonp_metadata({})",
+            serde_json::to_string(&self.metadata).context("serializing metadata")?
+        );
+        File::create(self.root_dir.path().join("metadata.jsonp"))
+            .await?
+            .write_all(metadata.as_bytes())
+            .await?;
         Ok(())
     }
 }
@@ -66,13 +104,21 @@ fn get_extension_from_url(file_url: &str) -> Result<String> {
 }
 
 async fn ensure_user_avatar(state: &mut ArchivalState, user: &mut User) -> Result<String> {
+    let pseudonymous = state.redactor.as_ref().is_some_and(Redactor::pseudonymizes);
     if let std::collections::hash_map::Entry::Vacant(e) = state.avatars.entry(user.id) {
-        let avatar_url = user.face();
-
-        let extension = get_extension_from_url(&avatar_url)?;
-        let file_path = state.assets_dir.join(format!("{}.{}", user.id, extension));
-
-        download_to_file(&avatar_url, &file_path).await?;
+        let file_path = if pseudonymous {
+            let file_path = state.assets_dir.join(format!("pseudonym_{}.png", user.id));
+            write_generated_avatar(user.id, &file_path)?;
+            file_path
+        } else {
+            let avatar_url = user.face();
+
+            let extension = get_extension_from_url(&avatar_url)?;
+            let file_path = state.assets_dir.join(format!("{}.{}", user.id, extension));
+
+            download_to_file(&avatar_url, &file_path).await?;
+            file_path
+        };
 
         e.insert(file_path.strip_prefix(&state.root_dir)?.to_path_buf());
     }
@@ -92,10 +138,61 @@ async fn ensure_user_avatar(state: &mut ArchivalState, user: &mut User) -> Resul
         .to_string())
 }
 
-async fn ensure_sticker<'a>(
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct LottieInfo {
+    version: Option<String>,
+    width: Option<f64>,
+    height: Option<f64>,
+    frame_rate: Option<f64>,
+    in_point: Option<f64>,
+    out_point: Option<f64>,
+}
+
+impl LottieInfo {
+    async fn read(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        let json: serde_json::Value =
+            serde_json::from_slice(&data).context("parsing lottie animation")?;
+        Ok(LottieInfo {
+            version: json["v"].as_str().map(|e| e.to_string()),
+            width: json["w"].as_f64(),
+            height: json["h"].as_f64(),
+            frame_rate: json["fr"].as_f64(),
+            in_point: json["ip"].as_f64(),
+            out_point: json["op"].as_f64(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StickerStore {
+    path: PathBuf,
+    name: String,
+    format: StickerFormatType,
+    pack: Option<String>,
+    description: Option<String>,
+    lottie: Option<LottieInfo>,
+}
+
+async fn sticker_pack_name<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    sticker: &Sticker,
+) -> Option<String> {
+    if let Some(pack) = sticker.pack_id {
+        return pack.to_sticker_pack(ctx).await.ok().map(|pack| pack.name);
+    }
+    let guild = sticker.guild_id?;
+    match guild.name(ctx.cache()) {
+        Some(name) => Some(name),
+        None => guild.to_partial_guild(ctx).await.ok().map(|e| e.name),
+    }
+}
+
+async fn ensure_sticker<'a, Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
     state: &'a mut ArchivalState,
     sticker: &StickerItem,
-) -> Result<&'a Path> {
+) -> Result<&'a StickerStore> {
     if let std::collections::hash_map::Entry::Vacant(e) = state.stickers.entry(sticker.id) {
         let image_url = sticker
             .image_url()
@@ -104,7 +201,28 @@ async fn ensure_sticker<'a>(
         let extension = get_extension_from_url(&image_url)?;
         let file_path = state.assets_dir.join(format!("{}.{extension}", sticker.id));
         download_to_file(&image_url, &file_path).await?;
-        e.insert(file_path.strip_prefix(&state.root_dir)?.to_path_buf());
+
+        // The sticker is still stored without its animation metadata
+        let lottie = if sticker.format_type == StickerFormatType::Lottie {
+            LottieInfo::read(&file_path).await.ok()
+        } else {
+            None
+        };
+
+        // Full sticker data is not available for stickers that got deleted
+        let (pack, description) = match sticker.to_sticker(ctx).await {
+            Ok(full) => (sticker_pack_name(ctx, &full).await, full.description),
+            Err(_) => (None, None),
+        };
+
+        e.insert(StickerStore {
+            path: file_path.strip_prefix(&state.root_dir)?.to_path_buf(),
+            name: sticker.name.clone(),
+            format: sticker.format_type,
+            pack,
+            description,
+            lottie,
+        });
     }
 
     Ok(state
@@ -170,44 +288,121 @@ async fn ensure_emoji<'a>(
 struct ReactionStore {
     path: PathBuf,
     count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<ReactionUserStore>>,
 }
 
-async fn process_message<Data: Send + Sync>(
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ReactionUserStore {
+    id: UserId,
+    name: String,
+    global_name: Option<String>,
+    bot: bool,
+    avatar: String,
+}
+
+/// Fetches every user that reacted with the given reaction
+///
+/// Users are requested in pages of 100, sequentially. Requests go through
+/// the serenity ratelimiter, which waits out route buckets and retries
+/// rate limited requests
+async fn fetch_reaction_users<Data: Send + Sync>(
     ctx: poise::Context<'_, Data, anyhow::Error>,
     state: &mut ArchivalState,
-    message: &mut Message,
+    message: &Message,
+    reaction: &ReactionType,
+) -> Result<Vec<ReactionUserStore>> {
+    const PAGE_SIZE: u8 = 100;
+    let mut users = vec![];
+    let mut after = None;
+    loop {
+        let page = message
+            .reaction_users(ctx, reaction.clone(), Some(PAGE_SIZE), after)
+            .await?;
+        let last_page = page.len() < PAGE_SIZE as usize;
+        after = page.last().map(|user| user.id);
+        for mut user in page {
+            if let Some(redactor) = &mut state.redactor {
+                redactor.user(&mut user);
+            }
+            let avatar = ensure_user_avatar(state, &mut user)
+                .await
+                .with_context(|| format!("fetching avatar of user {}", user.id))?;
+            users.push(ReactionUserStore {
+                id: user.id,
+                name: user.name,
+                global_name: user.global_name,
+                bot: user.bot,
+                avatar,
+            });
+        }
+        if last_page {
+            break;
+        }
+    }
+    Ok(users)
+}
+
+async fn localize_attachments(
+    state: &mut ArchivalState,
+    attachments: &mut [Attachment],
 ) -> Result<()> {
     let asset_path = &state.assets_dir;
     let root_dir_path = state.root_dir.path();
-    futures::future::join_all(
-        message
-            .attachments
-            .iter_mut()
+    let known = &state.attachments;
+    let downloaded = futures::future::join_all(
+        attachments
+            .iter()
+            .filter(|attachment| !known.contains_key(&attachment.id))
             .map(move |attachment| async move {
                 let filename = format!("{}_{}", attachment.id, attachment.filename);
                 let file_path = asset_path.join(&filename);
                 download_to_file(&attachment.url, &file_path)
                     .await
                     .context("Downloading attachment")?;
-                attachment.url = file_path
+                let path = file_path
                     .strip_prefix(root_dir_path)?
                     .to_str()
                     .ok_or_else(|| anyhow!("Bad file path"))?
                     .to_string();
-                Result::<()>::Ok(())
+                Result::<_>::Ok((attachment.id, path))
             }),
     )
     .await
     .into_iter()
-    .collect::<Result<Vec<()>, _>>()
-    .context("downloading attachments")?;
+    .collect::<Result<Vec<_>, _>>()?;
+
+    state.attachments.extend(downloaded);
+    for attachment in attachments {
+        attachment.url = state
+            .attachments
+            .get(&attachment.id)
+            .expect("Failed to retrieve attachment reference")
+            .clone();
+    }
+    Ok(())
+}
+
+async fn process_message_data<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    state: &mut ArchivalState,
+    message: &mut Message,
+    with_reaction_users: bool,
+) -> Result<serde_json::Value> {
+    if let Some(redactor) = &mut state.redactor {
+        redactor.message(message);
+    }
+
+    localize_attachments(state, &mut message.attachments)
+        .await
+        .context("downloading attachments")?;
 
     let mut stickers = vec![];
     for sticker in &message.sticker_items {
-        let path = ensure_sticker(state, sticker)
+        let store = ensure_sticker(ctx, state, sticker)
             .await
             .with_context(|| format!("Fetching sticker {}", sticker.id))?;
-        stickers.push((sticker.id, path.to_owned()));
+        stickers.push((sticker.id, store.clone()));
     }
     let stickers = stickers.into_iter().collect::<FxHashMap<_, _>>();
 
@@ -219,10 +414,23 @@ async fn process_message<Data: Send + Sync>(
     for reaction in &message.reactions {
         let path = ensure_emoji(state, &reaction.reaction_type)
             .await
-            .with_context(|| format!("fetching emoji {}", reaction.reaction_type))?;
+            .with_context(|| format!("fetching emoji {}", reaction.reaction_type))?
+            .to_path_buf();
+        let users = if with_reaction_users {
+            Some(
+                fetch_reaction_users(ctx, state, message, &reaction.reaction_type)
+                    .await
+                    .with_context(|| {
+                        format!("fetching users of reaction {}", reaction.reaction_type)
+                    })?,
+            )
+        } else {
+            None
+        };
         reactions.push(ReactionStore {
             count: reaction.count,
-            path: path.to_path_buf(),
+            path,
+            users,
         })
     }
 
@@ -271,13 +479,6 @@ async fn process_message<Data: Send + Sync>(
 
     // EMOJI_REGEX.find_iter(message.content)
 
-    let mut json_string = if state.processed_count > 0 {
-        ",\n"
-    } else {
-        "jsonp_parse([\n"
-    }
-    .to_string();
-
     let mut json_obj = serde_json::to_value(&message).context("serializing main message data")?;
     json_obj["reactions::processed"] =
         serde_json::to_value(reactions).context("serializing reactions")?;
@@ -288,6 +489,42 @@ async fn process_message<Data: Send + Sync>(
     json_obj["stickers::processed"] =
         serde_json::to_value(stickers).context("serializing used stickers")?;
     json_obj["author_avatar::processed"] = avatar.into();
+    extras::process_extras(ctx, state, message, &mut json_obj).await?;
+    Ok(json_obj)
+}
+
+async fn process_message<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    state: &mut ArchivalState,
+    message: &mut Message,
+) -> Result<()> {
+    // Referenced message is processed separately, so replies to messages
+    // outside the archived range still have their context
+    let referenced = match message.referenced_message.as_deref_mut() {
+        None => None,
+        Some(referenced) => {
+            referenced.guild_id = referenced.guild_id.or(message.guild_id);
+            Some(
+                process_message_data(ctx, state, referenced, false)
+                    .await
+                    .context("processing referenced message")?,
+            )
+        }
+    };
+
+    let with_reaction_users = state.options.reaction_users;
+    let mut json_obj = process_message_data(ctx, state, message, with_reaction_users).await?;
+    if let Some(referenced) = referenced {
+        json_obj["referenced_message"] = referenced;
+    }
+
+    let mut json_string = if state.processed_count > 0 {
+        ",\n"
+    } else {
+        "jsonp_parse([\n"
+    }
+    .to_string();
+
     json_string += &serde_json::to_string(&json_obj).context("stringifying json")?;
     state
         .file
@@ -312,6 +549,8 @@ async fn process_message<Data: Send + Sync>(
 pub struct ArchiveData {
     pub file: NamedTempFile,
     pub time_range: Range<Timestamp>,
+    /// ID and last edit time of every archived message
+    pub messages: ArchivedMessages,
 }
 
 pub async fn archive_messages<
@@ -322,41 +561,82 @@ pub async fn archive_messages<
 >(
     ctx: poise::Context<'_, Data, anyhow::Error>,
     messages: Messages,
+    options: ArchiveOptions,
     report: Reporter,
 ) -> Result<ArchiveData> {
-    let mut state = ArchivalState::create().await?;
+    let mut state = ArchivalState::create(options).await?;
+
+    if let Some(guild_id) = ctx.guild_id() {
+        metadata::snapshot_guild(ctx, &mut state, guild_id)
+            .await
+            .context("recording guild information")?;
+    }
 
     let mut messages = messages.boxed();
-    let mut last = Instant::now();
-    let mut last_count = 0;
+    let mut archived = ArchivedMessages::default();
+    let report = &report;
+    let mut reporter = CountingReporter::new(
+        Duration::from_secs(1),
+        Duration::from_secs(2),
+        0,
+        1,
+        10,
+        |status: String, is_due: bool| async move {
+            if is_due {
+                report(status).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        },
+    );
     while let Some(message) = messages.next().await {
         let mut message = message?;
-        if (last.elapsed().as_secs() >= 1 && state.processed_count > last_count)
-            && (last.elapsed().as_secs() >= 2 || state.processed_count - last_count >= 10)
-        {
-            last = Instant::now();
-            last_count = state.processed_count;
-            report(format!(
-                "Messages archived: {}\nCurrently processing: {}",
-                state.processed_count,
-                message.link()
-            ))
-            .await?;
+        reporter.current_count = state.processed_count;
+        if reporter.is_report_due() {
+            reporter
+                .force_report(format!(
+                    "Messages archived: {}\nCurrently processing: {}",
+                    reporter.progress(),
+                    message.link()
+                ))
+                .await?;
         }
+        metadata::snapshot_channel(ctx, &mut state, message.channel_id)
+            .await
+            .context("recording channel information")?;
+        // Recorded before processing, which may redact the message
+        let edited = message.edited_timestamp;
         process_message(ctx, &mut state, &mut message)
             .await
             .with_context(|| format!("processing message {}", message.link()))?;
+        archived.insert(message.id, edited);
     }
 
     state.finalize().await?;
 
+    if let Some(settings) = state.options.images.clone() {
+        report("Processing images".to_string()).await?;
+        let root = state.root_dir.path().to_path_buf();
+        tokio::task::spawn_blocking(move || process_images(&root, &settings))
+            .await?
+            .context("processing images")?;
+    }
+
     let time_range = state
         .time_range
         .unwrap_or_else(|| Timestamp::now()..Timestamp::now());
 
     report("Archiving files".to_string()).await?;
     let mut file = NamedTempFile::new().context("creating archive file")?;
-    archive_directory(state.root_dir.path(), file.as_file_mut()).context("zipping files")?;
+    write_directory(
+        state.root_dir.path(),
+        file.as_file_mut(),
+        &state.options.archive,
+    )
+    .context("archiving files")?;
 
-    Ok(ArchiveData { file, time_range })
+    Ok(ArchiveData {
+        file,
+        time_range,
+        messages: archived,
+    })
 }
@@ -0,0 +1,394 @@
+use super::{ensure_emoji, ensure_sticker, localize_attachments, ArchivalState, StickerStore};
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{
+    ActionRow, ActionRowComponent, AnswerId, Attachment, AttachmentId, ButtonKind, ButtonStyle,
+    Message, MessageType, Poll, PollMediaEmoji, ReactionType, StickerId, Timestamp,
+};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PollAnswerStore {
+    id: AnswerId,
+    text: Option<String>,
+    emoji: Option<PathBuf>,
+    votes: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PollStore {
+    question: Option<String>,
+    answers: Vec<PollAnswerStore>,
+    expiry: Option<Timestamp>,
+    allow_multiselect: bool,
+    finalized: bool,
+    total_votes: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct VoiceStore {
+    attachment: AttachmentId,
+    duration_secs: Option<f64>,
+    waveform: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SelectOptionStore {
+    label: String,
+    description: Option<String>,
+    emoji: Option<PathBuf>,
+    default: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ComponentStore {
+    Button {
+        label: Option<String>,
+        style: String,
+        url: Option<String>,
+        emoji: Option<PathBuf>,
+        disabled: bool,
+    },
+    Select {
+        placeholder: Option<String>,
+        options: Vec<SelectOptionStore>,
+        disabled: bool,
+    },
+    TextInput {
+        label: Option<String>,
+        placeholder: Option<String>,
+        value: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SystemStore {
+    kind: String,
+    text: String,
+}
+
+async fn optional_emoji(
+    state: &mut ArchivalState,
+    emoji: Option<&ReactionType>,
+) -> Result<Option<PathBuf>> {
+    let Some(emoji) = emoji else {
+        return Ok(None);
+    };
+    let path = ensure_emoji(state, emoji)
+        .await
+        .with_context(|| format!("fetching emoji {emoji}"))?;
+    Ok(Some(path.to_path_buf()))
+}
+
+async fn process_poll(state: &mut ArchivalState, poll: &Poll) -> Result<PollStore> {
+    let counts = poll
+        .results
+        .as_ref()
+        .map(|results| {
+            results
+                .answer_counts
+                .iter()
+                .map(|count| (count.id, count.count))
+                .collect::<FxHashMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    let mut answers = vec![];
+    for answer in &poll.answers {
+        let emoji = match &answer.poll_media.emoji {
+            None => None,
+            Some(PollMediaEmoji::Name(name)) => Some(ReactionType::Unicode(name.clone())),
+            Some(PollMediaEmoji::Id(id)) => Some(ReactionType::Custom {
+                animated: false,
+                id: *id,
+                name: None,
+            }),
+        };
+        answers.push(PollAnswerStore {
+            id: answer.answer_id,
+            text: answer.poll_media.text.clone(),
+            emoji: optional_emoji(state, emoji.as_ref()).await?,
+            votes: poll
+                .results
+                .as_ref()
+                .map(|_| counts.get(&answer.answer_id).copied().unwrap_or(0)),
+        });
+    }
+
+    Ok(PollStore {
+        question: poll.question.text.clone(),
+        total_votes: poll
+            .results
+            .as_ref()
+            .map(|_| answers.iter().filter_map(|e| e.votes).sum()),
+        answers,
+        expiry: poll.expiry,
+        allow_multiselect: poll.allow_multiselect,
+        finalized: poll
+            .results
+            .as_ref()
+            .map(|results| results.is_finalized)
+            .unwrap_or(false),
+    })
+}
+
+fn process_voice(attachments: &[Attachment]) -> Vec<VoiceStore> {
+    attachments
+        .iter()
+        .filter(|attachment| attachment.duration_secs.is_some() || attachment.waveform.is_some())
+        .map(|attachment| VoiceStore {
+            attachment: attachment.id,
+            duration_secs: attachment.duration_secs,
+            waveform: attachment.waveform.clone().unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn button_style(style: ButtonStyle) -> String {
+    match style {
+        ButtonStyle::Primary => "primary".to_string(),
+        ButtonStyle::Secondary => "secondary".to_string(),
+        ButtonStyle::Success => "success".to_string(),
+        ButtonStyle::Danger => "danger".to_string(),
+        other => format!("unknown_{}", u8::from(other)),
+    }
+}
+
+async fn process_components(
+    state: &mut ArchivalState,
+    rows: &[ActionRow],
+) -> Result<Vec<Vec<ComponentStore>>> {
+    let mut processed = vec![];
+    for row in rows {
+        let mut components = vec![];
+        for component in &row.components {
+            let component = match component {
+                ActionRowComponent::Button(button) => {
+                    let (style, url) = match &button.data {
+                        ButtonKind::Link { url } => ("link".to_string(), Some(url.clone())),
+                        ButtonKind::Premium { .. } => ("premium".to_string(), None),
+                        ButtonKind::NonLink { style, .. } => (button_style(*style), None),
+                    };
+                    ComponentStore::Button {
+                        label: button.label.clone(),
+                        style,
+                        url,
+                        emoji: optional_emoji(state, button.emoji.as_ref()).await?,
+                        disabled: button.disabled,
+                    }
+                }
+                ActionRowComponent::SelectMenu(menu) => {
+                    let mut options = vec![];
+                    for option in &menu.options {
+                        options.push(SelectOptionStore {
+                            label: option.label.clone(),
+                            description: option.description.clone(),
+                            emoji: optional_emoji(state, option.emoji.as_ref()).await?,
+                            default: option.default,
+                        });
+                    }
+                    ComponentStore::Select {
+                        placeholder: menu.placeholder.clone(),
+                        options,
+                        disabled: menu.disabled,
+                    }
+                }
+                ActionRowComponent::InputText(input) => ComponentStore::TextInput {
+                    label: input.label.clone(),
+                    placeholder: input.placeholder.clone(),
+                    value: input.value.clone(),
+                },
+                _ => continue,
+            };
+            components.push(component);
+        }
+        processed.push(components);
+    }
+    Ok(processed)
+}
+
+fn system_message(message: &Message) -> Option<SystemStore> {
+    let author = message.author.display_name();
+    let content = &message.content;
+    let (kind, text) = match message.kind {
+        MessageType::Regular
+        | MessageType::InlineReply
+        | MessageType::ChatInputCommand
+        | MessageType::ContextMenuCommand
+        | MessageType::AutoModAction => return None,
+        MessageType::GroupRecipientAddition => ("recipient_add", format!("{author} added a user")),
+        MessageType::GroupRecipientRemoval => {
+            ("recipient_remove", format!("{author} removed a user"))
+        }
+        MessageType::GroupCallCreation => ("call", format!("{author} started a call")),
+        MessageType::GroupNameUpdate => (
+            "channel_name_change",
+            format!("{author} changed the channel name: {content}"),
+        ),
+        MessageType::GroupIconUpdate => (
+            "channel_icon_change",
+            format!("{author} changed the channel icon"),
+        ),
+        MessageType::PinsAdd => (
+            "pins_add",
+            format!("{author} pinned a message to this channel"),
+        ),
+        MessageType::MemberJoin => ("member_join", format!("{author} joined the server")),
+        MessageType::NitroBoost => match content.parse::<u64>() {
+            Ok(times) if times > 1 => (
+                "boost",
+                format!("{author} just boosted the server {times} times!"),
+            ),
+            _ => ("boost", format!("{author} just boosted the server!")),
+        },
+        MessageType::NitroTier1 => (
+            "boost_tier",
+            format!("{author} just boosted the server! The server has achieved Level 1!"),
+        ),
+        MessageType::NitroTier2 => (
+            "boost_tier",
+            format!("{author} just boosted the server! The server has achieved Level 2!"),
+        ),
+        MessageType::NitroTier3 => (
+            "boost_tier",
+            format!("{author} just boosted the server! The server has achieved Level 3!"),
+        ),
+        MessageType::ChannelFollowAdd => (
+            "channel_follow_add",
+            format!("{author} has added {content} to this channel"),
+        ),
+        MessageType::GuildDiscoveryDisqualified => (
+            "discovery",
+            "This server has been removed from Server Discovery".to_string(),
+        ),
+        MessageType::GuildDiscoveryRequalified => (
+            "discovery",
+            "This server is eligible for Server Discovery again".to_string(),
+        ),
+        MessageType::GuildDiscoveryGracePeriodInitialWarning
+        | MessageType::GuildDiscoveryGracePeriodFinalWarning => (
+            "discovery",
+            "This server has failed Discovery activity requirements".to_string(),
+        ),
+        MessageType::ThreadCreated => (
+            "thread_created",
+            format!("{author} started a thread: {content}"),
+        ),
+        MessageType::ThreadStarterMessage => {
+            ("thread_starter", "Thread starter message".to_string())
+        }
+        MessageType::GuildInviteReminder => (
+            "invite_reminder",
+            "Wondering who to invite? Start by inviting anyone who can help you build the server!"
+                .to_string(),
+        ),
+        MessageType::RoleSubscriptionPurchase => {
+            let text = match &message.role_subscription_data {
+                Some(data) => format!(
+                    "{author} subscribed to {} ({} months)",
+                    data.tier_name, data.total_months_subscribed
+                ),
+                None => format!("{author} subscribed to the server"),
+            };
+            ("role_subscription", text)
+        }
+        MessageType::StageStart => ("stage_start", format!("{author} started {content}")),
+        MessageType::StageEnd => ("stage_end", format!("{author} ended {content}")),
+        MessageType::StageSpeaker => ("stage_speaker", format!("{author} is now a speaker")),
+        MessageType::StageTopic => (
+            "stage_topic",
+            format!("{author} changed the Stage topic: {content}"),
+        ),
+        MessageType::GuildIncidentAlertModeEnabled => {
+            ("incident", format!("{author} enabled security actions"))
+        }
+        MessageType::GuildIncidentAlertModeDisabled => {
+            ("incident", format!("{author} disabled security actions"))
+        }
+        MessageType::GuildIncidentReportRaid => ("incident", format!("{author} reported a raid")),
+        MessageType::GuildIncidentReportFalseAlarm => {
+            ("incident", format!("{author} reported a false alarm"))
+        }
+        other => (
+            "unknown",
+            format!("Unsupported system message (type {})", u8::from(other)),
+        ),
+    };
+    Some(SystemStore {
+        kind: kind.to_string(),
+        text,
+    })
+}
+
+/// Extracts polls, forwarded message snapshots, voice messages, components
+/// and system messages into explicit `::processed` fields of the message
+pub(super) async fn process_extras<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    state: &mut ArchivalState,
+    message: &mut Message,
+    json_obj: &mut serde_json::Value,
+) -> Result<()> {
+    if let Some(poll) = &message.poll {
+        let poll = process_poll(state, poll).await.context("processing poll")?;
+        json_obj["poll::processed"] = serde_json::to_value(poll).context("serializing poll")?;
+    }
+
+    let mut snapshots = vec![];
+    for snapshot in &mut message.message_snapshots {
+        localize_attachments(state, &mut snapshot.attachments)
+            .await
+            .context("downloading forwarded attachments")?;
+        let mut stickers: FxHashMap<StickerId, StickerStore> = FxHashMap::default();
+        for sticker in &snapshot.sticker_items {
+            let store = ensure_sticker(ctx, state, sticker)
+                .await
+                .with_context(|| format!("Fetching sticker {}", sticker.id))?;
+            stickers.insert(sticker.id, store.clone());
+        }
+        let mut snapshot_obj =
+            serde_json::to_value(&snapshot).context("serializing forwarded message")?;
+        snapshot_obj["stickers::processed"] =
+            serde_json::to_value(stickers).context("serializing forwarded stickers")?;
+        snapshot_obj["voice::processed"] =
+            serde_json::to_value(process_voice(&snapshot.attachments))
+                .context("serializing forwarded voice messages")?;
+        snapshot_obj["components::processed"] = serde_json::to_value(
+            process_components(state, &snapshot.components)
+                .await
+                .context("processing forwarded components")?,
+        )
+        .context("serializing forwarded components")?;
+        snapshots.push(snapshot_obj);
+    }
+    if !snapshots.is_empty() {
+        json_obj["snapshots::processed"] = snapshots.into();
+    }
+
+    let voice = process_voice(&message.attachments);
+    if !voice.is_empty() {
+        json_obj["voice::processed"] =
+            serde_json::to_value(voice).context("serializing voice messages")?;
+    }
+
+    if !message.components.is_empty() {
+        json_obj["components::processed"] = serde_json::to_value(
+            process_components(state, &message.components)
+                .await
+                .context("processing components")?,
+        )
+        .context("serializing components")?;
+    }
+
+    if let Some(mut system) = system_message(message) {
+        // Made from the author and content, but also role subscription names
+        if let Some(redactor) = &mut state.redactor {
+            system.text = redactor.text(&system.text);
+        }
+        json_obj["system::processed"] =
+            serde_json::to_value(system).context("serializing system message")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use super::{get_extension_from_url, ArchivalState};
+use anyhow::{Context, Result};
+use poise::serenity_prelude::{
+    Channel, ChannelId, ChannelType, GuildChannel, GuildId, MessageId, PermissionOverwrite,
+    PermissionOverwriteType, Permissions, PremiumTier, RoleId, Timestamp, UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use utils::web_files::download_to_file;
+
+/// Information about the archived guild and channels, as they were at the
+/// moment of archival
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(super) struct ArchiveMetadata {
+    pub snapshot_time: Timestamp,
+    pub guild: Option<GuildSnapshot>,
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+impl Default for ArchiveMetadata {
+    fn default() -> Self {
+        ArchiveMetadata {
+            snapshot_time: Timestamp::now(),
+            guild: None,
+            channels: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(super) struct GuildSnapshot {
+    id: GuildId,
+    name: String,
+    description: Option<String>,
+    owner_id: UserId,
+    premium_tier: PremiumTier,
+    approximate_member_count: Option<u64>,
+    icon: Option<PathBuf>,
+    banner: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ChannelRef {
+    id: ChannelId,
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+enum OverwriteTarget {
+    Role { id: RoleId, name: Option<String> },
+    Member { id: UserId, name: Option<String> },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct OverwriteSnapshot {
+    target: OverwriteTarget,
+    allow: Permissions,
+    deny: Permissions,
+    allow_names: Vec<String>,
+    deny_names: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(super) struct ChannelSnapshot {
+    pub id: ChannelId,
+    name: String,
+    kind: ChannelType,
+    topic: Option<String>,
+    parent: Option<ChannelRef>,
+    category: Option<ChannelRef>,
+    nsfw: bool,
+    slowmode_secs: Option<u16>,
+    position: u16,
+    created_at: Timestamp,
+    permission_overwrites: Vec<OverwriteSnapshot>,
+    /// Missing when the pins couldn't be fetched, such as without access
+    pinned_messages: Option<Vec<MessageId>>,
+}
+
+fn permission_names(permissions: Permissions) -> Vec<String> {
+    permissions
+        .get_permission_names()
+        .into_iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+async fn download_guild_image(
+    state: &ArchivalState,
+    url: Option<String>,
+    name: &str,
+) -> Result<Option<PathBuf>> {
+    let Some(url) = url else {
+        return Ok(None);
+    };
+    let extension = get_extension_from_url(&url)?;
+    let file_path = state.assets_dir.join(format!("{name}.{extension}"));
+    download_to_file(&url, &file_path).await?;
+    Ok(Some(file_path.strip_prefix(&state.root_dir)?.to_path_buf()))
+}
+
+pub(super) async fn snapshot_guild<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    state: &mut ArchivalState,
+    guild_id: GuildId,
+) -> Result<()> {
+    let guild = guild_id.to_partial_guild_with_counts(ctx).await?;
+    let icon = download_guild_image(state, guild.icon_url(), "guild_icon")
+        .await
+        .context("downloading guild icon")?;
+    let banner = download_guild_image(state, guild.banner_url(), "guild_banner")
+        .await
+        .context("downloading guild banner")?;
+    let owner_id = match &mut state.redactor {
+        Some(redactor) => redactor.user_id(guild.owner_id),
+        None => guild.owner_id,
+    };
+    state.metadata.guild = Some(GuildSnapshot {
+        id: guild.id,
+        name: guild.name,
+        description: guild.description,
+        owner_id,
+        premium_tier: guild.premium_tier,
+        approximate_member_count: guild.approximate_member_count,
+        icon,
+        banner,
+    });
+    Ok(())
+}
+
+async fn channel_ref<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    id: Option<ChannelId>,
+) -> Option<(ChannelRef, Option<GuildChannel>)> {
+    let id = id?;
+    let channel = id.to_channel(ctx).await.ok().and_then(Channel::guild);
+    Some((
+        ChannelRef {
+            id,
+            name: channel.as_ref().map(|e| e.name.clone()),
+        },
+        channel,
+    ))
+}
+
+async fn snapshot_overwrite<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    guild_id: GuildId,
+    roles: &HashMap<RoleId, String>,
+    overwrite: &PermissionOverwrite,
+) -> Option<OverwriteSnapshot> {
+    let target = match overwrite.kind {
+        PermissionOverwriteType::Role(id) => OverwriteTarget::Role {
+            id,
+            name: roles.get(&id).cloned(),
+        },
+        PermissionOverwriteType::Member(id) => OverwriteTarget::Member {
+            id,
+            name: guild_id
+                .member(ctx, id)
+                .await
+                .ok()
+                .map(|member| member.user.name),
+        },
+        _ => return None,
+    };
+    Some(OverwriteSnapshot {
+        target,
+        allow: overwrite.allow,
+        deny: overwrite.deny,
+        allow_names: permission_names(overwrite.allow),
+        deny_names: permission_names(overwrite.deny),
+    })
+}
+
+/// Records the channel information, unless it was already recorded
+pub(super) async fn snapshot_channel<Data: Send + Sync>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    state: &mut ArchivalState,
+    channel_id: ChannelId,
+) -> Result<()> {
+    if state.metadata.channels.iter().any(|e| e.id == channel_id) {
+        return Ok(());
+    }
+
+    let channel = channel_id
+        .to_channel(ctx)
+        .await?
+        .guild()
+        .ok_or_else(|| anyhow::anyhow!("Channel {channel_id} is not a guild channel"))?;
+
+    let cached_roles = ctx.guild().map(|guild| {
+        guild
+            .roles
+            .iter()
+            .map(|(id, role)| (*id, role.name.clone()))
+            .collect::<HashMap<_, _>>()
+    });
+    let roles = match cached_roles {
+        Some(roles) => roles,
+        None => channel
+            .guild_id
+            .to_partial_guild(ctx)
+            .await?
+            .roles
+            .into_iter()
+            .map(|(id, role)| (id, role.name))
+            .collect(),
+    };
+
+    let mut permission_overwrites = vec![];
+    for overwrite in &channel.permission_overwrites {
+        permission_overwrites
+            .extend(snapshot_overwrite(ctx, channel.guild_id, &roles, overwrite).await);
+    }
+    if let Some(redactor) = &mut state.redactor {
+        for overwrite in &mut permission_overwrites {
+            if let OverwriteTarget::Member { id, name } = &mut overwrite.target {
+                *name = name.take().map(|name| redactor.user_name(*id, name));
+                *id = redactor.user_id(*id);
+            }
+        }
+    }
+
+    let pinned_messages = channel_id
+        .pins(ctx)
+        .await
+        .ok()
+        .map(|pins| pins.into_iter().map(|e| e.id).collect());
+
+    // Threads are nested in a channel, which is in turn nested in a category
+    let parent = channel_ref(ctx, channel.parent_id).await;
+    let (parent, category) = match parent {
+        Some((parent, Some(parent_channel))) if channel.thread_metadata.is_some() => (
+            Some(parent),
+            channel_ref(ctx, parent_channel.parent_id)
+                .await
+                .map(|(category, _)| category),
+        ),
+        Some((category, _)) => (None, Some(category)),
+        None => (None, None),
+    };
+
+    state.metadata.channels.push(ChannelSnapshot {
+        id: channel.id,
+        name: channel.name,
+        kind: channel.kind,
+        topic: channel.topic,
+        parent,
+        category,
+        nsfw: channel.nsfw,
+        slowmode_secs: channel.rate_limit_per_user,
+        position: channel.position,
+        created_at: channel.id.created_at(),
+        permission_overwrites,
+        pinned_messages,
+    });
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Context, Result};
+use archival::doctor::diagnose_archive;
+use archival::integrity::{load_signing_key, parse_public_key, verify_archive};
+use ed25519_dalek::SigningKey;
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+use utils::encryption::DecryptionKeys;
+
+const USAGE: &str = "\
+Usage:
+  archive_tool keygen [--force] <key file>
+      Generate a signing key for the bot and print its public key. An
+      existing key file is only replaced with --force
+  archive_tool pubkey <key file>
+      Print the public key of a signing key
+  archive_tool verify <archive> [trusted public key]...
+      Check an archive against its signed manifest
+  archive_tool doctor <archive>
+      Check that an archive is intact and consistent
+
+Encrypted archives are read with these options of verify and doctor:
+  --passphrase <passphrase>
+      Passphrase of the archive entries
+  --identity <identity file>
+      age identity file, as written by age-keygen";
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Removes `--name <value>` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    let Some(i) = args.iter().position(|e| e == name) else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        bail!("{name} needs a value");
+    }
+    args.remove(i);
+    Ok(Some(args.remove(i)))
+}
+
+/// Removes `--name` from the arguments, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|e| e != name);
+    args.len() != len
+}
+
+/// Writes a new key file that only its owner can read
+fn write_key_file(path: &str, key: &SigningKey, force: bool) -> Result<()> {
+    if Path::new(path).exists() {
+        if !force {
+            bail!("{path} already exists, pass --force to replace it");
+        }
+        std::fs::remove_file(path).with_context(|| format!("removing {path}"))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("creating {path}"))?;
+    file.write_all(hex::encode(key.to_bytes()).as_bytes())
+        .context("writing signing key")?;
+    Ok(())
+}
+
+fn decryption_keys(args: &mut Vec<String>) -> Result<DecryptionKeys> {
+    let passphrase = take_option(args, "--passphrase")?;
+    let identities = match take_option(args, "--identity")? {
+        Some(path) => std::fs::read_to_string(&path)
+            .with_context(|| format!("reading identity file {path}"))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+        None => vec![],
+    };
+    Ok(DecryptionKeys {
+        passphrase,
+        identities,
+    })
+}
+
+fn run(mut args: Vec<String>) -> Result<bool> {
+    let decryption = decryption_keys(&mut args)?;
+    let force = take_flag(&mut args, "--force");
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["keygen", path] => {
+            let key = SigningKey::from_bytes(&rand::random());
+            write_key_file(path, &key, force)?;
+            println!("{}", hex::encode(key.verifying_key().as_bytes()));
+        }
+        ["pubkey", path] => {
+            let key = load_signing_key(Path::new(path))?;
+            println!("{}", hex::encode(key.verifying_key().as_bytes()));
+        }
+        ["verify", archive, keys @ ..] => {
+            let trusted = keys
+                .iter()
+                .map(|key| parse_public_key(key))
+                .collect::<Result<Vec<_>>>()?;
+            let report = verify_archive(Path::new(archive), &trusted, &decryption)?;
+            print!("{report}");
+            return Ok(report.is_intact());
+        }
+        ["doctor", archive] => {
+            let report = diagnose_archive(Path::new(archive), &decryption)?;
+            print!("{report}");
+            return Ok(report.is_healthy());
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
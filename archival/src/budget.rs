@@ -0,0 +1,263 @@
+//! Degrading of archived media until the archive fits a size limit
+
+use crate::records::{
+    attachment_objects, parse_messages, visit_attachments, write_messages, ArchiveReader,
+    ASSETS_PREFIX, MESSAGES_FILE,
+};
+use anyhow::{bail, Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::io::Write;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use utils::zip::ArchiveSettings;
+use zip::ZipWriter;
+
+/// Room left for the rewritten messages and zip headers
+const SIZE_MARGIN: u64 = 64 * 1024;
+
+/// How archived media gets degraded
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BudgetPolicy {
+    /// Longest side of downscaled images, in pixels
+    pub max_image_dimension: u32,
+    /// Quality of re-encoded images, from 1 to 100
+    pub jpeg_quality: u8,
+}
+
+impl Default for BudgetPolicy {
+    fn default() -> Self {
+        BudgetPolicy {
+            max_image_dimension: 1280,
+            jpeg_quality: 75,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    Dropped,
+    Downscaled,
+}
+
+/// Attachment that was degraded, stored in the `budget::processed` key of
+/// the message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BudgetRecord {
+    pub attachment: String,
+    pub action: BudgetAction,
+    pub original_url: Option<String>,
+    pub original_size: u64,
+}
+
+/// Degraded first to last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum AssetKind {
+    Video,
+    Image,
+    Other,
+}
+
+fn asset_kind(path: &str, content_type: Option<&str>) -> AssetKind {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    match (content_type, extension.as_deref()) {
+        (Some(kind), _) if kind.starts_with("video") => AssetKind::Video,
+        (Some(kind), _) if kind.starts_with("image") => AssetKind::Image,
+        (_, Some("mp4" | "webm" | "mov" | "mkv" | "avi")) => AssetKind::Video,
+        (_, Some("png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp")) => AssetKind::Image,
+        _ => AssetKind::Other,
+    }
+}
+
+enum Change {
+    Dropped,
+    Replaced { path: String, data: Vec<u8> },
+}
+
+pub struct BudgetResult {
+    pub file: NamedTempFile,
+    /// Number of attachments that were dropped or downscaled
+    pub affected: usize,
+}
+
+fn downscale(data: &[u8], policy: &BudgetPolicy) -> Result<Vec<u8>> {
+    let mut image = image::load_from_memory(data)?;
+    let max = policy.max_image_dimension;
+    if image.width() > max || image.height() > max {
+        image = image.resize(max, max, FilterType::Triangle);
+    }
+    let mut encoded = vec![];
+    JpegEncoder::new_with_quality(&mut encoded, policy.jpeg_quality)
+        .encode_image(&image.to_rgb8())?;
+    Ok(encoded)
+}
+
+/// Drops videos and downscales or drops images, largest first, until the
+/// archive at `path` fits into `size_limit`. Returns `None` if it already fits
+pub fn fit_archive(
+    path: &Path,
+    size_limit: u64,
+    policy: &BudgetPolicy,
+    settings: &ArchiveSettings,
+) -> Result<Option<BudgetResult>> {
+    let total = std::fs::metadata(path)?.len();
+    if total <= size_limit {
+        return Ok(None);
+    }
+
+    let mut archive = ArchiveReader::open(path)?;
+    let sizes = archive.compressed_sizes()?;
+    let messages = archive.read_string(MESSAGES_FILE)?;
+    let mut records = parse_messages(&messages)
+        .into_iter()
+        .map(serde_json::from_str::<Value>)
+        .collect::<Result<Vec<_>, _>>()
+        .context("parsing archived messages")?;
+
+    let mut candidates = FxHashMap::default();
+    for record in &mut records {
+        visit_attachments(record, &mut |parent| {
+            for attachment in attachment_objects(parent) {
+                let Some(url) = attachment.get("url").and_then(Value::as_str) else {
+                    continue;
+                };
+                if !url.starts_with(ASSETS_PREFIX) || !sizes.contains_key(url) {
+                    continue;
+                }
+                let content_type = attachment.get("content_type").and_then(Value::as_str);
+                let kind = asset_kind(url, content_type);
+                candidates.insert(url.to_string(), kind);
+            }
+        });
+    }
+    let mut candidates = candidates.into_iter().collect::<Vec<_>>();
+    candidates.sort_by_key(|(path, kind)| (*kind, Reverse(sizes[path])));
+
+    let mut excess = (total + SIZE_MARGIN).saturating_sub(size_limit);
+    let mut changes = FxHashMap::default();
+    for (path, kind) in candidates {
+        if excess == 0 {
+            break;
+        }
+        let compressed = sizes[&path];
+        let change = match kind {
+            AssetKind::Image => {
+                let mut data = vec![];
+                std::io::copy(&mut archive.zip.by_name(&path)?, &mut data)?;
+                match downscale(&data, policy) {
+                    Ok(data) if (data.len() as u64) < compressed => Change::Replaced {
+                        path: format!("{path}.budget.jpg"),
+                        data,
+                    },
+                    _ => Change::Dropped,
+                }
+            }
+            AssetKind::Video | AssetKind::Other => Change::Dropped,
+        };
+        let saved = match &change {
+            Change::Dropped => compressed,
+            Change::Replaced { data, .. } => compressed - data.len() as u64,
+        };
+        excess = excess.saturating_sub(saved);
+        changes.insert(path, change);
+    }
+    // Downscaling wasn't enough, drop the downscaled images as well
+    let mut replaced = changes
+        .iter()
+        .filter_map(|(path, change)| match change {
+            Change::Replaced { data, .. } => Some((path.clone(), data.len() as u64)),
+            Change::Dropped => None,
+        })
+        .collect::<Vec<_>>();
+    replaced.sort_by_key(|(_, size)| Reverse(*size));
+    for (path, size) in replaced {
+        if excess == 0 {
+            break;
+        }
+        excess = excess.saturating_sub(size);
+        changes.insert(path, Change::Dropped);
+    }
+    if excess > 0 {
+        bail!("Archive doesn't fit into the size limit even after degrading all attachments");
+    }
+
+    let mut affected = FxHashSet::default();
+    for record in &mut records {
+        visit_attachments(record, &mut |parent| {
+            let mut budgeted = vec![];
+            for attachment in attachment_objects(parent) {
+                let field = |name: &str| attachment.get(name).and_then(Value::as_str);
+                let Some(change) = field("url").and_then(|url| changes.get(url)) else {
+                    continue;
+                };
+                let id = field("id").unwrap_or_default().to_string();
+                let original_url = field("proxy_url").map(str::to_string);
+                let original_size = attachment
+                    .get("size")
+                    .and_then(Value::as_u64)
+                    .unwrap_or_default();
+                let action = match change {
+                    Change::Dropped => {
+                        let url = original_url.clone().unwrap_or_default();
+                        attachment.insert("url".to_string(), url.into());
+                        BudgetAction::Dropped
+                    }
+                    Change::Replaced { path, data } => {
+                        attachment.insert("url".to_string(), path.clone().into());
+                        attachment.insert("size".to_string(), data.len().into());
+                        attachment.insert("content_type".to_string(), "image/jpeg".into());
+                        BudgetAction::Downscaled
+                    }
+                };
+                affected.insert(id.clone());
+                budgeted.push(BudgetRecord {
+                    attachment: id,
+                    action,
+                    original_url,
+                    original_size,
+                });
+            }
+            if !budgeted.is_empty() {
+                parent.insert(
+                    "budget::processed".to_string(),
+                    serde_json::to_value(budgeted).expect("budget records serialize"),
+                );
+            }
+        });
+    }
+
+    let options = settings.zip_file_options();
+    let mut file = NamedTempFile::new().context("creating budgeted archive")?;
+    let mut zip = ZipWriter::new(file.as_file_mut());
+    for i in 0..archive.zip.len() {
+        let entry = archive.zip.by_index_raw(i)?;
+        if entry.name() == MESSAGES_FILE || changes.contains_key(entry.name()) {
+            continue;
+        }
+        zip.raw_copy_file(entry)?;
+    }
+    for change in changes.values() {
+        if let Change::Replaced { path, data } = change {
+            zip.start_file(path.as_str(), options)?;
+            zip.write_all(data)?;
+        }
+    }
+    let records = records
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    zip.start_file(MESSAGES_FILE, options)?;
+    zip.write_all(write_messages(records.iter().map(String::as_str)).as_bytes())?;
+    zip.finish()?;
+
+    Ok(Some(BudgetResult {
+        file,
+        affected: affected.len(),
+    }))
+}
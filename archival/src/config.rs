@@ -0,0 +1,95 @@
+use crate::budget::BudgetPolicy;
+use crate::images::ImageProcessing;
+use crate::integrity::SigningConfig;
+use crate::redaction::RedactionOptions;
+use poise::serenity_prelude::GuildId;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use utils::encryption::EncryptionConfig;
+use utils::web_files::hosting::HostingConfig;
+use utils::web_files::sinks::{default_sinks, SinkConfig};
+use utils::zip::ArchiveSettings;
+use wiper::config::WiperData;
+use wiper::wiping::PreservationRules;
+
+/// Archival settings, as they appear in the bot configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ArchivalConfig {
+    /// Where archives too large for a Discord attachment are stored, tried in order
+    pub sinks: Vec<SinkConfig>,
+    /// Post archives too large for a Discord attachment as several smaller
+    /// parts instead of sending them to the sinks
+    pub split_oversized: bool,
+    /// Attachment size limit in bytes, overriding the one derived from the
+    /// guild boost tier
+    pub upload_size_limit: Option<u64>,
+    /// Degrade the media of archives too large for a Discord attachment
+    /// until they fit, before splitting or sending them to the sinks
+    pub budget: Option<BudgetPolicy>,
+    /// Generate thumbnails and re-encode image attachments of every archive
+    pub images: Option<ImageProcessing>,
+    /// Container and compression of archives. Splitting, budgeting and the
+    /// built-in server only support zip
+    pub archive: ArchiveSettings,
+    /// Sign zip archives with a manifest of hashes, so they can be verified
+    /// later
+    pub signing: Option<SigningConfig>,
+    /// How archives requested with redaction are redacted
+    pub redaction: RedactionOptions,
+    /// Where the progress of member erasures is saved, so interrupted
+    /// erasures can be resumed
+    pub erasure_jobs_dir: PathBuf,
+    /// Settings specific to a guild, keyed by guild ID
+    pub guilds: HashMap<GuildId, GuildArchivalConfig>,
+}
+
+/// Archival settings of a single guild
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GuildArchivalConfig {
+    /// Encrypt archives before they are posted or stored anywhere
+    pub encryption: Option<EncryptionConfig>,
+    /// Messages that wiping after archival leaves in place
+    pub preserve: PreservationRules,
+}
+
+impl Default for ArchivalConfig {
+    fn default() -> Self {
+        ArchivalConfig {
+            sinks: default_sinks(),
+            split_oversized: false,
+            upload_size_limit: None,
+            budget: None,
+            images: None,
+            archive: ArchiveSettings::default(),
+            signing: None,
+            redaction: RedactionOptions::default(),
+            erasure_jobs_dir: PathBuf::from("erasure_jobs"),
+            guilds: HashMap::new(),
+        }
+    }
+}
+
+impl ArchivalConfig {
+    pub fn guild(&self, guild_id: Option<GuildId>) -> Option<&GuildArchivalConfig> {
+        self.guilds.get(&guild_id?)
+    }
+
+    /// Key uploaded archives of the guild are encrypted with
+    pub fn encryption(&self, guild_id: Option<GuildId>) -> Option<&EncryptionConfig> {
+        self.guild(guild_id)?.encryption.as_ref()
+    }
+}
+
+/// Bot data that provides the archival configuration. Archives can be wiped,
+/// so it provides the wiper configuration too
+pub trait ArchivalData: WiperData {
+    fn archival_config(&self) -> &ArchivalConfig;
+
+    /// Built-in server hosting, if enabled
+    fn archive_hosting(&self) -> Option<&HostingConfig> {
+        None
+    }
+}
@@ -0,0 +1,335 @@
+//! Validation of finished archives
+
+use crate::config::ArchivalData;
+use crate::integrity::{decryption_keys, INTEGRITY_FILE, SIGNATURE_FILE};
+use crate::records::{
+    parse_messages, parse_metadata, referenced_assets, ArchiveReader, ASSETS_PREFIX, MESSAGES_FILE,
+    METADATA_FILE, VIEWER_FILE,
+};
+use crate::send_report;
+use anyhow::{Context as AnyhowContext, Result};
+use poise::serenity_prelude::{Attachment, MessageId, Timestamp};
+use rustc_hash::FxHashSet;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use utils::command_handler_wrapper;
+use utils::encryption::DecryptionKeys;
+use zip::result::ZipError;
+
+#[macro_export]
+macro_rules! doctor_command {
+    ($name:ident, $data:ty) => {
+        $crate::archive_check_command!(
+            $name,
+            $data,
+            "Check that an archive is intact and consistent",
+            archival::doctor::doctor
+        );
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DoctorReport {
+    pub files: usize,
+    pub messages: usize,
+    pub assets: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl DoctorReport {
+    fn error(&mut self, message: impl Into<String>) {
+        self.issues.push(Issue {
+            severity: Severity::Error,
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, message: impl Into<String>) {
+        self.issues.push(Issue {
+            severity: Severity::Warning,
+            message: message.into(),
+        });
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|e| e.severity == severity)
+            .count()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.count(Severity::Error) == 0
+    }
+}
+
+impl Display for DoctorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{}, {}, {}",
+            pluralizer::pluralize("files", self.files as isize, true),
+            pluralizer::pluralize("messages", self.messages as isize, true),
+            pluralizer::pluralize("assets", self.assets as isize, true),
+        )?;
+        for severity in [Severity::Error, Severity::Warning] {
+            for issue in self.issues.iter().filter(|e| e.severity == severity) {
+                let label = match severity {
+                    Severity::Warning => "warning",
+                    Severity::Error => "error",
+                };
+                writeln!(f, "{label}: {}", issue.message)?;
+            }
+        }
+        writeln!(
+            f,
+            "{}, {}",
+            pluralizer::pluralize("errors", self.count(Severity::Error) as isize, true),
+            pluralizer::pluralize("warnings", self.count(Severity::Warning) as isize, true),
+        )
+    }
+}
+
+/// Creation time encoded in a message ID
+fn snowflake_time(id: &Value) -> Option<i64> {
+    let id = match id {
+        Value::String(id) => id.parse::<u64>().ok()?,
+        id => id.as_u64()?,
+    };
+    Some(MessageId::new(id).created_at().unix_timestamp())
+}
+
+fn timestamp(value: Option<&Value>) -> Option<Timestamp> {
+    Timestamp::parse(value?.as_str()?).ok()
+}
+
+/// Reads every entry, returning the number of encrypted entries that
+/// couldn't be checked for lack of a passphrase
+fn check_entries(reader: &mut ArchiveReader, report: &mut DoctorReport) -> usize {
+    let mut encrypted = 0;
+    for i in 0..reader.zip.len() {
+        if !reader.has_passphrase() && reader.zip.by_index_raw(i).is_ok_and(|e| e.encrypted()) {
+            encrypted += 1;
+            report.files += 1;
+            continue;
+        }
+        let result = reader
+            .entry(i)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut e| {
+                // Reading to the end checks the CRC
+                std::io::copy(&mut e, &mut std::io::sink())?;
+                Ok(e.is_file())
+            });
+        match result {
+            Ok(true) => report.files += 1,
+            Ok(false) => {}
+            Err(err) if is_wrong_passphrase(&err) => {
+                report.error(format!("entry {i} is encrypted with another passphrase"))
+            }
+            Err(err) => report.error(format!("entry {i} is corrupted: {err}")),
+        }
+    }
+    encrypted
+}
+
+fn is_wrong_passphrase(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ZipError>(),
+        Some(ZipError::InvalidPassword)
+    )
+}
+
+fn check_messages(
+    text: &str,
+    snapshot_time: Option<Timestamp>,
+    report: &mut DoctorReport,
+    referenced: &mut FxHashSet<String>,
+    existing: &FxHashSet<String>,
+) {
+    let mut previous: Option<Timestamp> = None;
+    let mut direction = None;
+    for (i, record) in parse_messages(text).into_iter().enumerate() {
+        let value = match serde_json::from_str::<Value>(record) {
+            Ok(value) => value,
+            Err(err) => {
+                report.error(format!("message record {i} doesn't parse: {err}"));
+                continue;
+            }
+        };
+        report.messages += 1;
+        let id = value
+            .get("id")
+            .map(|id| id.as_str().map_or_else(|| id.to_string(), str::to_string))
+            .unwrap_or_else(|| format!("#{i}"));
+
+        let mut assets = FxHashSet::default();
+        referenced_assets(&value, &mut assets);
+        for asset in &assets {
+            if !existing.contains(asset) {
+                report.error(format!("message {id} references missing {asset}"));
+            }
+        }
+        referenced.extend(assets);
+
+        let Some(time) = timestamp(value.get("timestamp")) else {
+            report.error(format!("message {id} has no valid timestamp"));
+            continue;
+        };
+        if let Some(created) = value.get("id").and_then(snowflake_time) {
+            if created != time.unix_timestamp() {
+                report.warning(format!(
+                    "message {id} timestamp {time} doesn't match its ID"
+                ));
+            }
+        }
+        if let Some(edited) = timestamp(value.get("edited_timestamp")) {
+            if edited < time {
+                report.warning(format!("message {id} was edited before it was sent"));
+            }
+        }
+        if snapshot_time.is_some_and(|snapshot| time > snapshot) {
+            report.warning(format!("message {id} was sent after the archive was made"));
+        }
+        // Messages are stored in one direction, either can be valid
+        if let Some(previous) = previous.filter(|e| *e != time) {
+            let ascending = time > previous;
+            match direction {
+                None => direction = Some(ascending),
+                Some(direction) if direction != ascending => {
+                    report.warning(format!("message {id} is out of order"));
+                }
+                _ => {}
+            }
+        }
+        previous = Some(time);
+    }
+}
+
+/// Validates the zip archive at `path`: that every entry is readable, that
+/// messages and metadata parse, that referenced assets exist and no assets
+/// are orphaned, and that message timestamps are consistent. Encrypted
+/// entries are only checked with their passphrase
+pub fn diagnose_archive(path: &Path, keys: &DecryptionKeys) -> Result<DoctorReport> {
+    let mut report = DoctorReport::default();
+    let mut reader = match ArchiveReader::open_with(path, keys) {
+        Ok(reader) => reader,
+        Err(err) => {
+            report.error(format!("{err:#}"));
+            return Ok(report);
+        }
+    };
+    let encrypted = check_entries(&mut reader, &mut report);
+    if encrypted > 0 {
+        report.warning(format!(
+            "{} encrypted, pass the passphrase to check the contents",
+            pluralizer::pluralize("entries", encrypted as isize, true)
+        ));
+        return Ok(report);
+    }
+
+    let existing = reader
+        .zip
+        .file_names()
+        .map(str::to_string)
+        .collect::<FxHashSet<_>>();
+    let assets = existing
+        .iter()
+        .filter(|e| e.starts_with(ASSETS_PREFIX) && !e.ends_with('/'))
+        .collect::<Vec<_>>();
+    report.assets = assets.len();
+    if !existing.contains(VIEWER_FILE) {
+        report.warning(format!("{VIEWER_FILE} is missing"));
+    }
+    if existing.contains(INTEGRITY_FILE) != existing.contains(SIGNATURE_FILE) {
+        report.error("integrity manifest or its signature is missing");
+    }
+
+    let mut referenced = FxHashSet::default();
+    let mut snapshot_time = None;
+    match reader.read_string(METADATA_FILE) {
+        Ok(text) => match serde_json::from_str::<Value>(parse_metadata(&text)) {
+            Ok(metadata) => {
+                snapshot_time = timestamp(metadata.get("snapshot_time"));
+                referenced_assets(&metadata, &mut referenced);
+            }
+            Err(err) => report.error(format!("{METADATA_FILE} doesn't parse: {err}")),
+        },
+        Err(_) => report.warning(format!("{METADATA_FILE} is missing")),
+    }
+    for asset in referenced.iter().filter(|e| !existing.contains(*e)) {
+        report.error(format!("metadata references missing {asset}"));
+    }
+
+    match reader.read_string(MESSAGES_FILE) {
+        Ok(text) => {
+            if !text.trim().starts_with("jsonp_parse([") || !text.trim().ends_with("])") {
+                report.error(format!(
+                    "{MESSAGES_FILE} is not wrapped in jsonp_parse([...])"
+                ));
+            }
+            check_messages(
+                &text,
+                snapshot_time,
+                &mut report,
+                &mut referenced,
+                &existing,
+            );
+        }
+        Err(err) => report.error(format!("{err:#}")),
+    }
+
+    let mut unreferenced = assets
+        .into_iter()
+        .filter(|e| !referenced.contains(*e))
+        .collect::<Vec<_>>();
+    unreferenced.sort();
+    for asset in unreferenced {
+        report.warning(format!("{asset} is not referenced by any message"));
+    }
+
+    Ok(report)
+}
+
+pub async fn doctor<T: ArchivalData>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    archive: Attachment,
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<()> {
+    command_handler_wrapper!(handle_doctor(ctx, archive, passphrase, identity))
+}
+
+async fn handle_doctor<T: ArchivalData>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    archive: Attachment,
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<()> {
+    ctx.defer_ephemeral().await?;
+    let keys = decryption_keys(ctx, passphrase, identity).await;
+    let mut file = NamedTempFile::new()?;
+    file.write_all(&archive.download().await.context("downloading archive")?)?;
+    let path = file.path().to_path_buf();
+    let report = tokio::task::spawn_blocking(move || diagnose_archive(&path, &keys))
+        .await?
+        .context("checking archive")?;
+    file.close()?;
+
+    send_report(ctx, &archive.filename, report.to_string()).await
+}
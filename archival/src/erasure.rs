@@ -0,0 +1,417 @@
+//! Erasure of everything a member posted across the guild. Jobs are saved
+//! after every channel, so an interrupted erasure picks up where it stopped
+
+use crate::config::ArchivalData;
+use crate::takeout::{export_user_messages, UserExport};
+use crate::Context;
+use anyhow::{Context as AnyhowContext, Result};
+use futures::TryStreamExt;
+use poise::serenity_prelude::{ButtonStyle, ChannelId, GuildId, Message, Timestamp, User, UserId};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use utils::command_handler_wrapper;
+use utils::component_tools::clear_components;
+use utils::confirmations::{confirm_buttons, BtnConfirmOptions};
+use utils::into_edit::IntoEdit;
+use utils::message_filter::MessageFilter;
+use utils::messages_iter::{guild_message_channels, smart_messages_iter, MessagesRange};
+use utils::web_files::messaged::{upload_file_and_message, UploadOptions};
+use wiper::wiping::{wipe_messages, WipeOptions};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+#[macro_export]
+macro_rules! erase_command {
+    ($name:ident, $data:ty) => {
+        /// Delete every message of a member across all channels and threads
+        #[poise::command(
+            slash_command,
+            prefix_command,
+            required_permissions = "MANAGE_MESSAGES",
+            default_member_permissions = "MANAGE_MESSAGES",
+            required_bot_permissions = "MANAGE_MESSAGES|READ_MESSAGE_HISTORY",
+            guild_only
+        )]
+        async fn $name(
+            ctx: poise::Context<'_, $data, anyhow::Error>,
+            #[description = "Member whose messages are erased"] user: poise::serenity_prelude::User,
+            #[description = "Post an export of the messages here before erasing them"]
+            archive_first: Option<bool>,
+            #[description = "ID of the member, to erase instead of only counting"]
+            confirmation: Option<String>,
+            #[description = "Discard the saved progress and count the messages again"]
+            recount: Option<bool>,
+        ) -> Result<()> {
+            archival::erasure::erase(
+                ctx,
+                user,
+                archive_first.unwrap_or(false),
+                confirmation,
+                recount.unwrap_or(false),
+            )
+            .await
+        }
+    };
+}
+
+/// Saved progress of the erasure of one member's messages
+#[derive(Debug, Serialize, Deserialize)]
+struct ErasureJob {
+    user_id: UserId,
+    counted_at: Timestamp,
+    /// Channels holding messages of the member
+    channels: Vec<ChannelProgress>,
+    /// Channels that couldn't be read, or only partially
+    unreadable: Vec<ChannelId>,
+    /// Whether the export was already posted
+    archived: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChannelProgress {
+    channel: ChannelId,
+    messages: usize,
+    erased: bool,
+}
+
+impl ErasureJob {
+    fn path(dir: &Path, guild_id: GuildId, user_id: UserId) -> PathBuf {
+        dir.join(format!("{guild_id}-{user_id}.json"))
+    }
+
+    async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data).context("parsing saved erasure job")?,
+            )),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context("reading saved erasure job"),
+        }
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Write aside and rename, so a crash never leaves a truncated job
+        let temp = path.with_extension("json.tmp");
+        tokio::fs::write(&temp, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&temp, path)
+            .await
+            .context("saving erasure job")
+    }
+
+    fn remaining(&self) -> impl Iterator<Item = &ChannelProgress> {
+        self.channels.iter().filter(|e| !e.erased)
+    }
+
+    fn describe(&self) -> String {
+        let remaining = self.remaining().collect::<Vec<_>>();
+        let messages = remaining.iter().map(|e| e.messages).sum::<usize>();
+        let mut text = format!(
+            "Found {} of <@{}> in {}, counted <t:{}:R>",
+            pluralizer::pluralize("messages", messages as isize, true),
+            self.user_id,
+            pluralizer::pluralize("channels", remaining.len() as isize, true),
+            self.counted_at.unix_timestamp()
+        );
+        let erased = self.channels.len() - remaining.len();
+        if erased > 0 {
+            text.push_str(&format!(
+                "\nResuming an earlier erasure, {} already done",
+                pluralizer::pluralize("channels", erased as isize, true)
+            ));
+        }
+        if !self.unreadable.is_empty() {
+            let channels = self
+                .unreadable
+                .iter()
+                .map(|e| format!("<#{e}>"))
+                .collect::<Vec<_>>();
+            text.push_str(&format!(
+                "\nThese channels could not be read completely: {}",
+                channels.join(", ")
+            ));
+        }
+        text
+    }
+}
+
+pub async fn erase<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    user: User,
+    archive_first: bool,
+    confirmation: Option<String>,
+    recount: bool,
+) -> Result<()> {
+    command_handler_wrapper!(handle_erase(
+        ctx,
+        user,
+        archive_first,
+        confirmation,
+        recount
+    ))
+}
+
+fn user_filter(user: &User) -> MessageFilter {
+    MessageFilter {
+        authors: vec![user.id],
+        ..Default::default()
+    }
+}
+
+/// Counts the messages of `user` in every channel of the guild
+async fn count_messages<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    guild_id: GuildId,
+    user: &User,
+    reply: &Message,
+) -> Result<ErasureJob> {
+    let listing = guild_message_channels(ctx, guild_id)
+        .await
+        .context("listing channels")?;
+    let channels = listing.channels;
+    let mut job = ErasureJob {
+        user_id: user.id,
+        counted_at: Timestamp::now(),
+        channels: vec![],
+        // Channels with threads that couldn't be listed
+        unreadable: listing.incomplete,
+        archived: false,
+    };
+    let mut found = 0;
+    let mut last_report = Instant::now();
+    for (i, channel) in channels.iter().enumerate() {
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            let text = format!(
+                "Counting messages of <@{}>\nChannel {} of {}: <#{}>\nMessages found: {found}",
+                user.id,
+                i + 1,
+                channels.len(),
+                channel.id
+            );
+            let _ = reply
+                .channel_id
+                .edit_message(ctx, reply.id, text.into_edit())
+                .await;
+        }
+        let messages = user_filter(user).apply(
+            smart_messages_iter(ctx, channel.id, MessagesRange::unbounded())
+                .map_err(anyhow::Error::from),
+        );
+        let mut count = 0;
+        let result = messages
+            .try_for_each(|_| {
+                count += 1;
+                futures::future::ready(Ok(()))
+            })
+            .await;
+        if result.is_err() {
+            job.unreadable.push(channel.id);
+        }
+        if count > 0 {
+            found += count;
+            job.channels.push(ChannelProgress {
+                channel: channel.id,
+                messages: count,
+                erased: false,
+            });
+        }
+    }
+    Ok(job)
+}
+
+async fn post_export<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    user: &User,
+    job: &ErasureJob,
+    reply: &Message,
+) -> Result<()> {
+    let config = ctx.data().archival_config();
+    let channels = job.remaining().map(|e| e.channel).collect::<Vec<_>>();
+    let export = export_user_messages(ctx, user, &channels, reply).await?;
+    let mut prefix = format!(
+        "Export of the messages of <@{}> made before erasing them",
+        user.id
+    );
+    if let Some(note) = export.notes() {
+        prefix.push('\n');
+        prefix.push_str(&note);
+    }
+    let UserExport { file, filename, .. } = export;
+    upload_file_and_message(
+        ctx,
+        ctx.channel_id(),
+        file.as_file(),
+        file.path(),
+        filename,
+        prefix,
+        UploadOptions {
+            sinks: &config.sinks,
+            size_limit_override: config.upload_size_limit,
+        },
+    )
+    .await?;
+    file.close()?;
+    Ok(())
+}
+
+async fn handle_erase<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    user: User,
+    archive_first: bool,
+    confirmation: Option<String>,
+    recount: bool,
+) -> Result<()> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow::anyhow!("Erasure is only available in guilds"))?;
+    let config = ctx.data().archival_config();
+    let path = ErasureJob::path(&config.erasure_jobs_dir, guild_id, user.id);
+
+    let mut reply = ctx.say("Listing channels").await?.into_message().await?;
+    let saved = match recount {
+        true => None,
+        false => ErasureJob::load(&path).await?,
+    };
+    let mut job = match saved {
+        Some(job) => job,
+        None => {
+            let job = count_messages(ctx, guild_id, &user, &reply).await?;
+            job.save(&path).await?;
+            job
+        }
+    };
+
+    let summary = job.describe();
+    if job.remaining().next().is_none() {
+        tokio::fs::remove_file(&path).await?;
+        reply
+            .edit(ctx, format!("{summary}\nNothing to erase").into_edit())
+            .await?;
+        return Ok(());
+    }
+
+    if confirmation.as_deref().map(str::trim) != Some(user.id.to_string().as_str()) {
+        let text = format!(
+            "{summary}\nNothing was deleted. To erase these messages, run the command again \
+            with `confirmation` set to `{}`",
+            user.id
+        );
+        reply.edit(ctx, text.into_edit()).await?;
+        return Ok(());
+    }
+
+    let messages = job.remaining().map(|e| e.messages).sum::<usize>();
+    reply
+        .edit(
+            ctx,
+            format!("{summary}\nThis can't be undone. Erase them?").into_edit(),
+        )
+        .await?;
+    let confirmed = confirm_buttons(
+        ctx,
+        &mut reply,
+        BtnConfirmOptions {
+            confirm_text: format!(
+                "Erase {}",
+                pluralizer::pluralize("messages", messages as isize, true)
+            ),
+            confirm_style: ButtonStyle::Danger,
+            cancel_text: "Cancel".to_string(),
+            cancel_style: ButtonStyle::Primary,
+            timeout: Duration::from_secs(30),
+        },
+    )
+    .await?
+    .bool();
+    clear_components(ctx, &mut reply).await?;
+    if !confirmed {
+        reply
+            .edit(ctx, format!("{summary}\nErasure canceled").into_edit())
+            .await?;
+        return Ok(());
+    }
+
+    if archive_first && !job.archived {
+        post_export(ctx, &user, &job, &reply)
+            .await
+            .context("exporting messages before erasure")?;
+        job.archived = true;
+        job.save(&path).await?;
+    }
+
+    let total = job.remaining().count();
+    let mut failed = vec![];
+    let mut position = 0;
+    for i in 0..job.channels.len() {
+        if job.channels[i].erased {
+            continue;
+        }
+        position += 1;
+        let channel = job.channels[i].channel;
+        let prefix = format!(
+            "Erasing messages of <@{}>\nChannel {position} of {total}: <#{channel}>",
+            user.id,
+        );
+        let status = &reply;
+        let prefix = &prefix;
+        let result = wipe_messages(
+            ctx,
+            || {
+                user_filter(&user).apply(
+                    smart_messages_iter(ctx, channel, MessagesRange::unbounded())
+                        .map_err(anyhow::Error::from),
+                )
+            },
+            WipeOptions {
+                expected: Some(job.channels[i].messages),
+                ..Default::default()
+            },
+            |text, is_due| async move {
+                if is_due {
+                    status
+                        .channel_id
+                        .edit_message(ctx, status.id, format!("{prefix}\n{text}").into_edit())
+                        .await?;
+                }
+                Ok(())
+            },
+        )
+        .await;
+        match result {
+            Ok(summary) if summary.is_complete() => {
+                job.channels[i].erased = true;
+                job.save(&path).await?;
+            }
+            Ok(summary) => failed.push(format!(
+                "<#{channel}>: {} left",
+                pluralizer::pluralize(
+                    "messages",
+                    (summary.failed.len() + summary.leftover.len()) as isize,
+                    true
+                )
+            )),
+            Err(err) => failed.push(format!("<#{channel}>: {err:#}")),
+        }
+    }
+
+    let erased = job.channels.iter().filter(|e| e.erased).count();
+    let mut text = format!(
+        "Erased messages of <@{}> in {}",
+        user.id,
+        pluralizer::pluralize("channels", erased as isize, true)
+    );
+    if failed.is_empty() {
+        tokio::fs::remove_file(&path).await?;
+    } else {
+        text.push_str(&format!(
+            "\nErasure failed in these channels, run the command again to retry:\n{}",
+            failed.join("\n")
+        ));
+    }
+    reply.edit(ctx, text.into_edit()).await?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+//! Thumbnailing and re-encoding of archived images
+
+use crate::records::{
+    attachment_objects, parse_messages, visit_attachments, write_messages, ASSETS_PREFIX,
+    MESSAGES_FILE,
+};
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageEncoder};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+const THUMBNAILS_DIR: &str = "assets/thumbnails";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    Jpeg,
+    /// Lossless, the quality setting doesn't apply
+    Webp,
+}
+
+/// How archived images get processed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ImageProcessing {
+    /// Longest side of thumbnails, in pixels
+    pub thumbnail_size: u32,
+    /// Re-encode PNG images to this format, when it makes them smaller
+    pub reencode: Option<ImageFormat>,
+    /// Quality of JPEG thumbnails and re-encodes, from 1 to 100. WebP
+    /// re-encodes are lossless and ignore it
+    pub quality: u8,
+}
+
+impl Default for ImageProcessing {
+    fn default() -> Self {
+        ImageProcessing {
+            thumbnail_size: 400,
+            reencode: None,
+            quality: 85,
+        }
+    }
+}
+
+/// Thumbnail of an image attachment, stored in the `thumbnails::processed`
+/// key of the message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailRecord {
+    pub attachment: String,
+    pub thumbnail: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+struct ProcessedImage {
+    thumbnail: Option<String>,
+    width: u32,
+    height: u32,
+    /// New path and size of the re-encoded original
+    reencoded: Option<(String, u64)>,
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = vec![];
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut encoded, quality).encode_image(&image.to_rgb8())?
+        }
+        ImageFormat::Webp => {
+            let image = image.to_rgba8();
+            WebPEncoder::new_lossless(&mut encoded).write_image(
+                &image,
+                image.width(),
+                image.height(),
+                image::ExtendedColorType::Rgba8,
+            )?
+        }
+    }
+    Ok(encoded)
+}
+
+fn is_processable(path: &str, content_type: Option<&str>) -> bool {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    // Animated images would lose their animation
+    match (content_type, extension.as_deref()) {
+        (_, Some("gif")) | (Some("image/gif"), _) => false,
+        (Some(kind), _) if kind.starts_with("image") => true,
+        (_, Some("png" | "jpg" | "jpeg" | "webp" | "bmp")) => true,
+        _ => false,
+    }
+}
+
+fn process_image(
+    root: &Path,
+    path: &str,
+    settings: &ImageProcessing,
+) -> Result<Option<ProcessedImage>> {
+    let data = std::fs::read(root.join(path))?;
+    // Not every file that claims to be an image is one
+    let Ok(image) = image::load_from_memory(&data) else {
+        return Ok(None);
+    };
+    let (width, height) = (image.width(), image.height());
+    let stem = Path::new(path)
+        .file_stem()
+        .and_then(|e| e.to_str())
+        .unwrap_or("image");
+
+    let thumbnail = if width.max(height) > settings.thumbnail_size {
+        let small = image.thumbnail(settings.thumbnail_size, settings.thumbnail_size);
+        let (encoded, extension) = if small.color().has_alpha() {
+            let small = small.to_rgba8();
+            let mut encoded = vec![];
+            PngEncoder::new(&mut encoded).write_image(
+                &small,
+                small.width(),
+                small.height(),
+                image::ExtendedColorType::Rgba8,
+            )?;
+            (encoded, "png")
+        } else {
+            (encode(&small, ImageFormat::Jpeg, settings.quality)?, "jpg")
+        };
+        let thumbnail = format!("{THUMBNAILS_DIR}/{stem}.{extension}");
+        std::fs::write(root.join(&thumbnail), encoded)?;
+        Some(thumbnail)
+    } else {
+        None
+    };
+
+    let is_png = path.to_lowercase().ends_with(".png");
+    let reencoded = match settings.reencode {
+        // JPEG would lose the transparency
+        Some(ImageFormat::Jpeg) if image.color().has_alpha() => None,
+        Some(format) if is_png => {
+            let encoded = encode(&image, format, settings.quality)?;
+            if encoded.len() < data.len() {
+                let extension = match format {
+                    ImageFormat::Jpeg => "jpg",
+                    ImageFormat::Webp => "webp",
+                };
+                let new_path = format!("{}.{extension}", path.strip_suffix(".png").unwrap_or(path));
+                std::fs::write(root.join(&new_path), &encoded)?;
+                std::fs::remove_file(root.join(path))?;
+                Some((new_path, encoded.len() as u64))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    Ok(Some(ProcessedImage {
+        thumbnail,
+        width,
+        height,
+        reencoded,
+    }))
+}
+
+/// Generates thumbnails for the image attachments in the archive directory,
+/// re-encoding them if configured. Returns the number of processed images
+pub fn process_images(root: &Path, settings: &ImageProcessing) -> Result<usize> {
+    let messages_path = root.join(MESSAGES_FILE);
+    let messages = std::fs::read_to_string(&messages_path).context("reading messages")?;
+    let mut records = parse_messages(&messages)
+        .into_iter()
+        .map(serde_json::from_str::<Value>)
+        .collect::<Result<Vec<_>, _>>()
+        .context("parsing archived messages")?;
+    std::fs::create_dir_all(root.join(THUMBNAILS_DIR))?;
+
+    let mut processed: FxHashMap<String, Option<ProcessedImage>> = FxHashMap::default();
+    for record in &mut records {
+        let mut result = Ok(());
+        visit_attachments(record, &mut |parent| {
+            let mut thumbnails = vec![];
+            for attachment in attachment_objects(parent) {
+                let field = |name: &str| attachment.get(name).and_then(Value::as_str);
+                let Some(url) = field("url").map(str::to_string) else {
+                    continue;
+                };
+                if !url.starts_with(ASSETS_PREFIX) || !is_processable(&url, field("content_type")) {
+                    continue;
+                }
+                if !processed.contains_key(&url) {
+                    match process_image(root, &url, settings)
+                        .with_context(|| format!("processing image {url}"))
+                    {
+                        Ok(image) => processed.insert(url.clone(), image),
+                        Err(err) => {
+                            result = Err(err);
+                            return;
+                        }
+                    };
+                }
+                let Some(image) = &processed[&url] else {
+                    continue;
+                };
+                let id = field("id").unwrap_or_default().to_string();
+
+                if let Some((path, size)) = &image.reencoded {
+                    attachment.insert("url".to_string(), path.clone().into());
+                    attachment.insert("size".to_string(), (*size).into());
+                    let content_type = if path.ends_with(".webp") {
+                        "image/webp"
+                    } else {
+                        "image/jpeg"
+                    };
+                    attachment.insert("content_type".to_string(), content_type.into());
+                }
+                if let Some(thumbnail) = &image.thumbnail {
+                    thumbnails.push(ThumbnailRecord {
+                        attachment: id,
+                        thumbnail: thumbnail.clone(),
+                        width: image.width,
+                        height: image.height,
+                    });
+                }
+            }
+            if !thumbnails.is_empty() {
+                parent.insert(
+                    "thumbnails::processed".to_string(),
+                    serde_json::to_value(thumbnails).expect("thumbnail records serialize"),
+                );
+            }
+        });
+        result?;
+    }
+
+    let records = records
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    std::fs::write(
+        messages_path,
+        write_messages(records.iter().map(String::as_str)),
+    )?;
+
+    Ok(processed.values().filter(|e| e.is_some()).count())
+}
@@ -0,0 +1,572 @@
+//! Signed manifests that make modifications of finished archives detectable
+
+use crate::config::ArchivalData;
+use crate::records::{parse_messages, ArchiveReader, MESSAGES_FILE};
+use crate::send_report;
+use anyhow::{bail, Context as AnyhowContext, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use poise::serenity_prelude::Attachment;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use utils::command_handler_wrapper;
+use utils::encryption::DecryptionKeys;
+use utils::zip::ArchiveSettings;
+use zip::ZipWriter;
+
+pub const INTEGRITY_FILE: &str = "integrity.json";
+pub const SIGNATURE_FILE: &str = "integrity.json.sig";
+const MESSAGE_CHUNK_SIZE: usize = 100;
+const MANIFEST_VERSION: u32 = 1;
+
+/// Command that checks an uploaded archive with `$check`. Keys are passed as
+/// arguments, so it is a slash command with ephemeral replies only, and it
+/// falls back to the guild's passphrase, so it is limited to moderators
+#[macro_export]
+macro_rules! archive_check_command {
+    ($name:ident, $data:ty, $description:literal, $check:path) => {
+        #[doc = $description]
+        #[poise::command(
+            slash_command,
+            ephemeral,
+            required_permissions = "MANAGE_MESSAGES",
+            default_member_permissions = "MANAGE_MESSAGES",
+            guild_only
+        )]
+        async fn $name(
+            ctx: poise::Context<'_, $data, anyhow::Error>,
+            #[description = "Archive to check"] archive: poise::serenity_prelude::Attachment,
+            #[description = "Passphrase of an encrypted archive, defaults to the server's"]
+            passphrase: Option<String>,
+            #[description = "age identity (AGE-SECRET-KEY-1...) of an age-encrypted archive"]
+            identity: Option<String>,
+        ) -> Result<()> {
+            $check(ctx, archive, passphrase, identity).await
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! verify_command {
+    ($name:ident, $data:ty) => {
+        $crate::archive_check_command!(
+            $name,
+            $data,
+            "Check whether an archive was modified after the bot signed it",
+            archival::integrity::verify
+        );
+    };
+}
+
+/// Signing of archives, as it appears in the bot configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningConfig {
+    /// File holding the hex-encoded ed25519 secret key, as written by
+    /// `archive_tool keygen`
+    pub key_file: PathBuf,
+    /// Hex-encoded public keys of earlier signing keys that are still trusted
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+}
+
+impl SigningConfig {
+    pub fn signing_key(&self) -> Result<SigningKey> {
+        load_signing_key(&self.key_file)
+    }
+
+    /// Keys whose signatures are accepted, the current one included
+    pub fn trusted_keys(&self) -> Result<Vec<VerifyingKey>> {
+        let mut keys = vec![self.signing_key()?.verifying_key()];
+        for key in &self.trusted_keys {
+            keys.push(parse_public_key(key)?);
+        }
+        Ok(keys)
+    }
+}
+
+/// Hashes of everything in an archive, stored in [INTEGRITY_FILE]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityManifest {
+    pub version: u32,
+    /// SHA-256 of every file, by path
+    pub files: BTreeMap<String, String>,
+    /// SHA-256 of consecutive runs of message records, so modified messages
+    /// can be narrowed down
+    pub message_chunks: Vec<MessageChunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageChunk {
+    pub first_message: Option<String>,
+    pub last_message: Option<String>,
+    pub count: usize,
+    pub sha256: String,
+}
+
+/// Signature over the exact bytes of [INTEGRITY_FILE], stored in
+/// [SIGNATURE_FILE]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading signing key at {}", path.display()))?;
+    let bytes: [u8; 32] = hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Signing key must be 32 hex-encoded bytes"))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub fn parse_public_key(text: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Public key must be 32 hex-encoded bytes"))?;
+    VerifyingKey::from_bytes(&bytes).context("parsing public key")
+}
+
+fn is_integrity_file(name: &str) -> bool {
+    name == INTEGRITY_FILE || name == SIGNATURE_FILE
+}
+
+fn hash_files(reader: &mut ArchiveReader) -> Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+    for i in 0..reader.zip.len() {
+        let mut entry = reader.entry(i)?;
+        if !entry.is_file() || is_integrity_file(entry.name()) {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut entry, &mut hasher).with_context(|| format!("hashing {name}"))?;
+        files.insert(name, hex::encode(hasher.finalize()));
+    }
+    Ok(files)
+}
+
+fn message_id(record: &str) -> Option<String> {
+    let value = serde_json::from_str::<Value>(record).ok()?;
+    match value.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        id => Some(id.to_string()),
+    }
+}
+
+fn message_chunks(messages: &str) -> Vec<MessageChunk> {
+    parse_messages(messages)
+        .chunks(MESSAGE_CHUNK_SIZE)
+        .map(|chunk| MessageChunk {
+            first_message: chunk.first().and_then(|e| message_id(e)),
+            last_message: chunk.last().and_then(|e| message_id(e)),
+            count: chunk.len(),
+            sha256: hex::encode(Sha256::digest(chunk.join("\n"))),
+        })
+        .collect()
+}
+
+fn build_manifest(reader: &mut ArchiveReader) -> Result<IntegrityManifest> {
+    let files = hash_files(reader)?;
+    let message_chunks = match files.contains_key(MESSAGES_FILE) {
+        true => message_chunks(&reader.read_string(MESSAGES_FILE)?),
+        false => vec![],
+    };
+    Ok(IntegrityManifest {
+        version: MANIFEST_VERSION,
+        files,
+        message_chunks,
+    })
+}
+
+/// Writes a copy of the zip archive at `path` with a manifest signed by `key`
+pub fn sign_archive(
+    path: &Path,
+    key: &SigningKey,
+    settings: &ArchiveSettings,
+) -> Result<NamedTempFile> {
+    let mut reader = ArchiveReader::open(path)?;
+    let manifest = serde_json::to_vec_pretty(&build_manifest(&mut reader)?)?;
+    let signature = ManifestSignature {
+        public_key: hex::encode(key.verifying_key().as_bytes()),
+        signature: hex::encode(key.sign(&manifest).to_bytes()),
+    };
+
+    let mut out = NamedTempFile::new()?;
+    let mut zip = ZipWriter::new(out.as_file_mut());
+    for i in 0..reader.zip.len() {
+        let entry = reader.zip.by_index_raw(i)?;
+        if !is_integrity_file(entry.name()) {
+            zip.raw_copy_file(entry)?;
+        }
+    }
+    let options = settings.zip_file_options();
+    zip.start_file(INTEGRITY_FILE, options)?;
+    zip.write_all(&manifest)?;
+    zip.start_file(SIGNATURE_FILE, options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&signature)?)?;
+    zip.finish()?;
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// The signature doesn't match the manifest
+    Invalid,
+    /// The manifest is signed by a key that isn't trusted
+    UnknownKey,
+    /// The manifest is signed by a trusted key
+    Trusted,
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub signature: SignatureStatus,
+    pub public_key: String,
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    pub added: Vec<String>,
+    /// Chunks of messages that differ, as recorded in the manifest
+    pub modified_messages: Vec<MessageChunk>,
+    /// Number of message records in the manifest and in the archive, when
+    /// they differ
+    pub message_count_change: Option<(usize, usize)>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.signature == SignatureStatus::Trusted
+            && self.modified.is_empty()
+            && self.missing.is_empty()
+            && self.added.is_empty()
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.signature {
+            SignatureStatus::Invalid => writeln!(
+                f,
+                "Signature is INVALID, the manifest was modified (key {})",
+                self.public_key
+            )?,
+            SignatureStatus::UnknownKey => writeln!(
+                f,
+                "Signature is valid, but key {} is not trusted",
+                self.public_key
+            )?,
+            SignatureStatus::Trusted => {
+                writeln!(f, "Signature is valid, signed by {}", self.public_key)?
+            }
+        }
+        let lists = [
+            ("Modified files", &self.modified),
+            ("Missing files", &self.missing),
+            ("Added files", &self.added),
+        ];
+        for (title, files) in lists {
+            if !files.is_empty() {
+                writeln!(f, "{title}:")?;
+                for file in files {
+                    writeln!(f, "- {file}")?;
+                }
+            }
+        }
+        if let Some((expected, found)) = self.message_count_change {
+            writeln!(f, "Archive has {found} messages, manifest lists {expected}")?;
+        }
+        if !self.modified_messages.is_empty() {
+            writeln!(f, "Modified messages:")?;
+            for chunk in &self.modified_messages {
+                writeln!(
+                    f,
+                    "- {} messages from {} to {}",
+                    chunk.count,
+                    chunk.first_message.as_deref().unwrap_or("?"),
+                    chunk.last_message.as_deref().unwrap_or("?")
+                )?;
+            }
+        }
+        if self.is_intact() {
+            writeln!(f, "Archive is intact")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the archive at `path` against its signed manifest. With no trusted
+/// keys, any valid signature is reported as [SignatureStatus::UnknownKey]
+pub fn verify_archive(
+    path: &Path,
+    trusted: &[VerifyingKey],
+    keys: &DecryptionKeys,
+) -> Result<VerifyReport> {
+    let mut reader = ArchiveReader::open_with(path, keys)?;
+    if !reader.has_passphrase() && reader.encrypted_entries()? > 0 {
+        bail!("Archive is encrypted, its passphrase is needed to verify it");
+    }
+    if reader.zip.index_for_name(INTEGRITY_FILE).is_none() {
+        bail!("Archive is not signed");
+    }
+    let manifest_text = reader.read_string(INTEGRITY_FILE)?;
+    let signature: ManifestSignature = serde_json::from_str(&reader.read_string(SIGNATURE_FILE)?)
+        .context("parsing manifest signature")?;
+    let public_key = parse_public_key(&signature.public_key)?;
+
+    let signature_status = match hex::decode(&signature.signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .filter(|sig| public_key.verify(manifest_text.as_bytes(), sig).is_ok())
+    {
+        None => SignatureStatus::Invalid,
+        Some(_) if trusted.contains(&public_key) => SignatureStatus::Trusted,
+        Some(_) => SignatureStatus::UnknownKey,
+    };
+
+    let expected: IntegrityManifest =
+        serde_json::from_str(&manifest_text).context("parsing integrity manifest")?;
+    let actual = build_manifest(&mut reader)?;
+
+    let mut modified = vec![];
+    let mut missing = vec![];
+    for (name, hash) in &expected.files {
+        match actual.files.get(name) {
+            None => missing.push(name.clone()),
+            Some(actual) if actual != hash => modified.push(name.clone()),
+            _ => {}
+        }
+    }
+    let added = actual
+        .files
+        .keys()
+        .filter(|name| !expected.files.contains_key(*name))
+        .cloned()
+        .collect();
+
+    let modified_messages = expected
+        .message_chunks
+        .iter()
+        .enumerate()
+        .filter(|(i, chunk)| actual.message_chunks.get(*i) != Some(chunk))
+        .map(|(_, chunk)| chunk.clone())
+        .collect();
+    let count = |chunks: &[MessageChunk]| chunks.iter().map(|e| e.count).sum::<usize>();
+    let message_count_change = Some((
+        count(&expected.message_chunks),
+        count(&actual.message_chunks),
+    ))
+    .filter(|(expected, actual)| expected != actual);
+
+    Ok(VerifyReport {
+        signature: signature_status,
+        public_key: signature.public_key,
+        modified,
+        missing,
+        added,
+        modified_messages,
+        message_count_change,
+    })
+}
+
+pub async fn verify<T: ArchivalData>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    archive: Attachment,
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<()> {
+    command_handler_wrapper!(handle_verify(ctx, archive, passphrase, identity))
+}
+
+/// Keys for reading an uploaded archive. The passphrase defaults to the one
+/// configured for the guild, for members who can manage messages
+pub(crate) async fn decryption_keys<T: ArchivalData>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> DecryptionKeys {
+    let passphrase = match passphrase {
+        Some(passphrase) => Some(passphrase),
+        None if can_manage_messages(ctx).await => ctx
+            .data()
+            .archival_config()
+            .encryption(ctx.guild_id())
+            .and_then(|e| e.passphrase())
+            .map(str::to_string),
+        None => None,
+    };
+    DecryptionKeys {
+        passphrase,
+        identities: identity.into_iter().collect(),
+    }
+}
+
+async fn can_manage_messages<T: Send + Sync>(ctx: poise::Context<'_, T, anyhow::Error>) -> bool {
+    ctx.author_member()
+        .await
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.manage_messages())
+}
+
+async fn handle_verify<T: ArchivalData>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    archive: Attachment,
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<()> {
+    ctx.defer_ephemeral().await?;
+    let trusted = match &ctx.data().archival_config().signing {
+        Some(signing) => signing.trusted_keys()?,
+        None => vec![],
+    };
+    let keys = decryption_keys(ctx, passphrase, identity).await;
+
+    let mut file = NamedTempFile::new()?;
+    file.write_all(&archive.download().await.context("downloading archive")?)?;
+    let path = file.path().to_path_buf();
+    let report = tokio::task::spawn_blocking(move || verify_archive(&path, &trusted, &keys))
+        .await?
+        .context("verifying archive")?;
+    file.close()?;
+
+    send_report(ctx, &archive.filename, report.to_string()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::write_messages;
+    use utils::zip::write_directory;
+
+    const MESSAGE_COUNT: usize = 250;
+
+    fn message(id: usize, content: &str) -> String {
+        serde_json::json!({ "id": id.to_string(), "content": content }).to_string()
+    }
+
+    fn unsigned_archive() -> NamedTempFile {
+        let dir = tempfile::tempdir().unwrap();
+        let messages = (1..=MESSAGE_COUNT)
+            .map(|id| message(id, "hello"))
+            .collect::<Vec<_>>();
+        std::fs::write(
+            dir.path().join(MESSAGES_FILE),
+            write_messages(messages.iter().map(String::as_str)),
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("assets")).unwrap();
+        std::fs::write(dir.path().join("assets/1.png"), "image").unwrap();
+        let mut archive = NamedTempFile::new().unwrap();
+        write_directory(dir.path(), archive.as_file_mut(), &Default::default()).unwrap();
+        archive
+    }
+
+    /// Copy of the archive with the contents of `name` replaced
+    fn replace_entry(path: &Path, name: &str, content: &str) -> NamedTempFile {
+        let mut reader = ArchiveReader::open(path).unwrap();
+        let mut out = NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(out.as_file_mut());
+        for i in 0..reader.zip.len() {
+            let entry = reader.zip.by_index_raw(i).unwrap();
+            if entry.name() == name {
+                zip.start_file(name, ArchiveSettings::default().zip_file_options())
+                    .unwrap();
+                zip.write_all(content.as_bytes()).unwrap();
+            } else {
+                zip.raw_copy_file(entry).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+        out
+    }
+
+    fn signed(key: &SigningKey) -> NamedTempFile {
+        sign_archive(unsigned_archive().path(), key, &Default::default()).unwrap()
+    }
+
+    fn verify(path: &Path, trusted: &[VerifyingKey]) -> VerifyReport {
+        verify_archive(path, trusted, &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn signed_archive_is_intact() {
+        let key = SigningKey::from_bytes(&rand::random());
+        let report = verify(signed(&key).path(), &[key.verifying_key()]);
+        assert_eq!(report.signature, SignatureStatus::Trusted);
+        assert!(report.is_intact(), "{report}");
+        assert!(report.modified_messages.is_empty());
+        assert_eq!(report.message_count_change, None);
+    }
+
+    #[test]
+    fn unsigned_archive_is_rejected() {
+        let trusted = [SigningKey::from_bytes(&rand::random()).verifying_key()];
+        let path = unsigned_archive();
+        assert!(verify_archive(path.path(), &trusted, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn tampered_file_is_reported() {
+        let key = SigningKey::from_bytes(&rand::random());
+        let archive = signed(&key);
+        let tampered = replace_entry(archive.path(), "assets/1.png", "other image");
+        let report = verify(tampered.path(), &[key.verifying_key()]);
+        assert_eq!(report.signature, SignatureStatus::Trusted);
+        assert_eq!(report.modified, ["assets/1.png"]);
+        assert!(!report.is_intact());
+    }
+
+    #[test]
+    fn tampered_message_chunk_is_reported() {
+        let key = SigningKey::from_bytes(&rand::random());
+        let archive = signed(&key);
+        let messages = (1..=MESSAGE_COUNT)
+            .map(|id| message(id, if id == 150 { "edited" } else { "hello" }))
+            .collect::<Vec<_>>();
+        let tampered = replace_entry(
+            archive.path(),
+            MESSAGES_FILE,
+            &write_messages(messages.iter().map(String::as_str)),
+        );
+        let report = verify(tampered.path(), &[key.verifying_key()]);
+        assert_eq!(report.modified, [MESSAGES_FILE]);
+        assert_eq!(report.modified_messages.len(), 1);
+        let chunk = &report.modified_messages[0];
+        assert_eq!(chunk.first_message.as_deref(), Some("101"));
+        assert_eq!(chunk.last_message.as_deref(), Some("200"));
+        assert_eq!(report.message_count_change, None);
+        assert!(!report.is_intact());
+    }
+
+    #[test]
+    fn tampered_manifest_is_reported() {
+        let key = SigningKey::from_bytes(&rand::random());
+        let archive = signed(&key);
+        let mut reader = ArchiveReader::open(archive.path()).unwrap();
+        let manifest = reader.read_string(INTEGRITY_FILE).unwrap();
+        let tampered = replace_entry(
+            archive.path(),
+            INTEGRITY_FILE,
+            &manifest.replace("\"version\": 1", "\"version\":1"),
+        );
+        let report = verify(tampered.path(), &[key.verifying_key()]);
+        assert_eq!(report.signature, SignatureStatus::Invalid);
+        assert!(!report.is_intact());
+    }
+
+    #[test]
+    fn untrusted_key_is_reported() {
+        let key = SigningKey::from_bytes(&rand::random());
+        let other = SigningKey::from_bytes(&rand::random());
+        let report = verify(signed(&key).path(), &[other.verifying_key()]);
+        assert_eq!(report.signature, SignatureStatus::UnknownKey);
+        assert!(report.modified.is_empty());
+        assert!(!report.is_intact());
+    }
+}
@@ -1,21 +1,44 @@
-use crate::archival::{archive_messages, ArchiveData};
+use crate::archival::{archive_messages, ArchiveData, ArchiveOptions};
+use crate::budget::fit_archive;
+use crate::config::ArchivalData;
+use crate::integrity::sign_archive;
+use crate::split::{split_archive, upload_split_archive, SplitArchive};
 use anyhow::Error;
 use anyhow::{Context as AnyhowContext, Result};
+use ed25519_dalek::SigningKey;
 use futures::TryStreamExt;
-use poise::serenity_prelude::{ButtonStyle, Timestamp, UserId};
+use poise::serenity_prelude::{ButtonStyle, CreateAttachment, Timestamp, UserId};
+use poise::CreateReply;
 use std::time::Duration;
+use tempfile::NamedTempFile;
 use tokio::time::sleep;
 use utils::command_handler_wrapper;
 use utils::component_tools::{clear_components, set_dummy_text_component};
 use utils::confirmations::{confirm_buttons, BtnConfirmOptions};
+use utils::encryption::EncryptionConfig;
 use utils::into_edit::IntoEdit;
+use utils::message_filter::MessageFilter;
 use utils::messages_iter::{smart_messages_iter, MessagesRange};
-use utils::web_files::messaged::upload_file_and_message;
-use wiper::wiping::wipe_messages;
+use utils::web_files::messaged::{effective_size_limit, upload_file_and_message, UploadOptions};
+use utils::zip::{ArchiveFormat, ArchiveSettings};
+use wiper::wiping::{wipe_messages, WipeOptions};
 
 pub mod archival;
+pub mod budget;
+pub mod config;
+pub mod doctor;
+pub mod erasure;
+pub mod images;
+pub mod integrity;
+pub mod records;
+pub mod redaction;
+pub mod split;
+pub mod takeout;
 
-type Context<'a, T> = poise::Context<'a, T, Error>;
+pub(crate) type Context<'a, T> = poise::Context<'a, T, Error>;
+
+pub(crate) const ENCRYPTED_NOTE: &str =
+    "Archive is encrypted with the key configured for this server";
 
 #[macro_export]
 macro_rules! archive_command {
@@ -29,33 +52,77 @@ macro_rules! archive_command {
             required_bot_permissions = "MANAGE_MESSAGES|READ_MESSAGE_HISTORY",
             guild_only
         )]
+        #[allow(clippy::too_many_arguments)]
         async fn $name(
             ctx: poise::Context<'_, $data, anyhow::Error>,
             #[description = "Name of the archive"] archive_name: String,
+            #[description = "Store the users of every reaction (slow for large channels)"]
+            reaction_users: Option<bool>,
+            #[description = "Replace users with pseudonyms and redact personal data, for sharing"]
+            redact: Option<bool>,
+            #[description = "Only messages of these users, as mentions or IDs"] authors: Option<
+                String,
+            >,
+            #[description = "Leave out messages of bots"] exclude_bots: Option<bool>,
+            #[description = "Only messages containing this text"] contains: Option<String>,
+            #[description = "Only messages matching this regular expression"] matching: Option<
+                String,
+            >,
+            #[description = "Only messages with attachments"] with_attachments: Option<bool>,
+            #[description = "Only pinned messages"] pinned_only: Option<bool>,
         ) -> Result<()> {
-            archival::archive(ctx, archive_name).await
+            let filter = utils::message_filter::MessageFilter {
+                authors: authors
+                    .as_deref()
+                    .map(utils::message_filter::MessageFilter::parse_authors)
+                    .unwrap_or_default(),
+                exclude_bots: exclude_bots.unwrap_or(false),
+                keyword: contains,
+                pattern: matching
+                    .as_deref()
+                    .map(utils::message_filter::MessageFilter::parse_pattern)
+                    .transpose()?,
+                with_attachments: with_attachments.unwrap_or(false),
+                pinned_only: pinned_only.unwrap_or(false),
+                ..Default::default()
+            };
+            let options = archival::archival::ArchiveOptions {
+                reaction_users: reaction_users.unwrap_or(false),
+                redaction: redact.unwrap_or(false).then(Default::default),
+                ..Default::default()
+            };
+            archival::archive(ctx, archive_name, filter, options).await
         }
     };
 }
 
-pub async fn archive<T: Sync + Send>(ctx: Context<'_, T>, archive_name: String) -> Result<()> {
+pub async fn archive<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    archive_name: String,
+    filter: MessageFilter,
+    options: ArchiveOptions,
+) -> Result<()> {
     command_handler_wrapper!(handle_archive(
         ctx,
         MessagesRange::unbounded(),
         archive_name,
+        filter,
+        options,
     ))
 }
 
-async fn handle_archive<T: Sync + Send>(
+async fn handle_archive<T: ArchivalData>(
     ctx: Context<'_, T>,
     mut messages_range: MessagesRange,
     mut archive_name: String,
+    filter: MessageFilter,
+    options: ArchiveOptions,
 ) -> Result<()> {
-    let mut reply = ctx
-        .say("Are you sure you want to archive this channel?")
-        .await?
-        .into_message()
-        .await?;
+    let question = match filter.is_empty() {
+        true => "Are you sure you want to archive this channel?".to_string(),
+        false => format!("Are you sure you want to archive {filter} in this channel?"),
+    };
+    let mut reply = ctx.say(question).await?.into_message().await?;
 
     let confirmed = confirm_buttons(
         ctx,
@@ -87,10 +154,30 @@ async fn handle_archive<T: Sync + Send>(
     reply.edit(ctx, "Archival in progress".into_edit()).await?;
 
     let response_id = reply.id;
+    let config = ctx.data().archival_config();
+    // A redacted archive doesn't preserve the messages, so they are never
+    // offered for wiping
+    let redacted = options.redaction.is_some();
+    let options = ArchiveOptions {
+        images: config.images.clone(),
+        archive: config.archive.clone(),
+        // The command only toggles redaction, its settings are configured
+        redaction: options.redaction.map(|_| config.redaction.clone()),
+        ..options
+    };
+    let is_zip = config.archive.format == ArchiveFormat::Zip;
+    let encryption = config.encryption(ctx.guild_id());
 
-    let ArchiveData { file, time_range } = archive_messages(
+    let ArchiveData {
+        file,
+        time_range,
+        messages: archived,
+    } = archive_messages(
         ctx,
-        smart_messages_iter(ctx, ctx.channel_id(), messages_range).map_err(|e| e.into()),
+        filter.clone().apply(
+            smart_messages_iter(ctx, ctx.channel_id(), messages_range).map_err(|e| e.into()),
+        ),
+        options,
         |status| async {
             ctx.channel_id()
                 .edit_message(ctx, response_id, status.into_edit())
@@ -100,6 +187,19 @@ async fn handle_archive<T: Sync + Send>(
     )
     .await?;
 
+    // Budgeting and splitting rewrite the archive, so their results get
+    // signed again
+    let signing_key = config
+        .signing
+        .as_ref()
+        .filter(|_| is_zip)
+        .map(|signing| signing.signing_key())
+        .transpose()?;
+    let file = match &signing_key {
+        Some(key) => sign_file(key, file.path(), &config.archive).await?,
+        None => file,
+    };
+
     let date_string = {
         let start_day = time_range.start.date_naive();
         let end_day = time_range.end.date_naive();
@@ -114,8 +214,8 @@ async fn handle_archive<T: Sync + Send>(
         }
     };
 
-    // Assume user id
-    if let Ok(id) = archive_name.parse::<u64>() {
+    // Assume user id, only named when the archive isn't redacted
+    if let Some(id) = archive_name.parse::<u64>().ok().filter(|_| !redacted) {
         let user = UserId::from(id);
         if let Ok(user) = user.to_user(ctx).await {
             if let Some(discriminator) = user.discriminator {
@@ -126,29 +226,176 @@ async fn handle_archive<T: Sync + Send>(
         }
     }
 
-    let filename = format!("{} - {archive_name}.zip", date_string);
+    let filename = format!(
+        "{} - {archive_name}.{}",
+        date_string,
+        config.archive.extension()
+    );
+    let uploaded_filename =
+        encryption.map_or_else(|| filename.clone(), |e| e.encrypted_filename(&filename));
 
     reply.edit(ctx, "Uploading archive".into_edit()).await?;
 
     let timeout = 60 * 15;
 
-    let edit_prefix = format!(
-        "Archival successful. Archive name: `{filename}`\nMessages deletion will automatically be canceled <t:{}:R>",
-        Timestamp::now().unix_timestamp() + timeout
-    );
+    let mut edit_prefix = match redacted {
+        true => format!(
+            "Archival successful. Archive name: `{uploaded_filename}`\nArchive is redacted, so messages can't be wiped after it"
+        ),
+        false => format!(
+            "Archival successful. Archive name: `{uploaded_filename}`\nMessages deletion will automatically be canceled <t:{}:R>",
+            Timestamp::now().unix_timestamp() + timeout
+        ),
+    };
 
-    let mut latest_message = upload_file_and_message(
-        ctx,
-        ctx.channel_id(),
-        file.as_file(),
-        file.path(),
-        filename.to_string(),
-        edit_prefix,
-    )
-    .await?;
+    if encryption.is_some() {
+        edit_prefix.push('\n');
+        edit_prefix.push_str(ENCRYPTED_NOTE);
+    }
+
+    if !is_zip {
+        let skipped = [
+            ("hosting", ctx.data().archive_hosting().is_some()),
+            ("size budgeting", config.budget.is_some()),
+            ("splitting", config.split_oversized),
+            ("signing", config.signing.is_some()),
+        ]
+        .iter()
+        .filter(|(_, configured)| *configured)
+        .map(|(feature, _)| *feature)
+        .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            edit_prefix.push_str(&format!(
+                "\nSkipped {}, they only support zip archives",
+                skipped.join(", ")
+            ));
+        }
+    }
+
+    // Hosted archives don't need to go to the fallback sinks. Encrypted
+    // archives can't be browsed, so they are never hosted
+    let mut sinks = config.sinks.as_slice();
+    if let Some(hosting) = ctx
+        .data()
+        .archive_hosting()
+        .filter(|_| is_zip && encryption.is_none())
+    {
+        let hosted = hosting
+            .store(file.path(), &filename)
+            .await
+            .context("storing archive on the built-in server")?;
+        edit_prefix.push_str(&format!(
+            "\nBrowse archive at {}\nDownload at {}\nLinks will expire <t:{}:R>",
+            hosted.link,
+            hosted.download_link,
+            hosted.expires.unix_timestamp()
+        ));
+        sinks = &[];
+    }
+
+    let size_limit = effective_size_limit(ctx, config.upload_size_limit).await;
+
+    let budgeted = match config.budget.as_ref().filter(|_| is_zip) {
+        Some(policy) => {
+            let path = file.path().to_path_buf();
+            let policy = policy.clone();
+            let settings = config.archive.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                fit_archive(&path, size_limit, &policy, &settings)
+            })
+            .await?;
+            match result {
+                Ok(Some(result)) => {
+                    edit_prefix.push_str(&format!(
+                        "\n{} reduced or removed to fit the upload limit",
+                        pluralizer::pluralize("attachments", result.affected as isize, true)
+                    ));
+                    match &signing_key {
+                        Some(key) => {
+                            Some(sign_file(key, result.file.path(), &config.archive).await?)
+                        }
+                        None => Some(result.file),
+                    }
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    edit_prefix.push_str(&format!("\nArchive could not be reduced: {err}"));
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let file = budgeted.unwrap_or(file);
+
+    let split =
+        if is_zip && config.split_oversized && file.as_file().metadata()?.len() >= size_limit {
+            let path = file.path().to_path_buf();
+            let split_filename = filename.clone();
+            let settings = config.archive.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                split_archive(&path, &split_filename, size_limit, &settings)
+            })
+            .await?;
+            match result {
+                Ok(split) => Some(split),
+                Err(err) => {
+                    edit_prefix.push_str(&format!("\nArchive could not be split: {err}"));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+    let split = match (&signing_key, split) {
+        (Some(key), Some(mut split)) => {
+            for part in &mut split.parts {
+                part.file = sign_file(key, part.file.path(), &config.archive).await?;
+            }
+            Some(split)
+        }
+        (_, split) => split,
+    };
+
+    let (file, split) = match (encryption, split) {
+        (None, split) => (file, split),
+        (Some(encryption), Some(split)) => (
+            file,
+            Some(encrypt_split(encryption, split, &config.archive).await?),
+        ),
+        (Some(encryption), None) => (
+            encrypt_archive(encryption, file.path(), &config.archive).await?,
+            None,
+        ),
+    };
+
+    let mut latest_message = match split {
+        Some(split) => upload_split_archive(ctx, ctx.channel_id(), split, edit_prefix).await?,
+        None => {
+            upload_file_and_message(
+                ctx,
+                ctx.channel_id(),
+                file.as_file(),
+                file.path(),
+                uploaded_filename,
+                edit_prefix,
+                UploadOptions {
+                    sinks,
+                    size_limit_override: config.upload_size_limit,
+                },
+            )
+            .await?
+        }
+    };
 
     reply.delete(ctx).await?;
 
+    if redacted {
+        file.close()?;
+        return Ok(());
+    }
+
     let confirmed = confirm_buttons(
         ctx,
         &mut latest_message,
@@ -174,9 +421,20 @@ async fn handle_archive<T: Sync + Send>(
             .id;
         let channel = ctx.channel_id();
 
-        wipe_messages(
+        let preserve = ctx.data().preservation_rules(ctx.guild_id());
+        let summary = wipe_messages(
             ctx,
-            smart_messages_iter(ctx, ctx.channel_id(), messages_range).map_err(|e| e.into()),
+            || {
+                filter.clone().apply(
+                    smart_messages_iter(ctx, ctx.channel_id(), messages_range)
+                        .map_err(|e| e.into()),
+                )
+            },
+            WipeOptions {
+                expected: Some(archived.len()),
+                archived: Some(archived),
+                preserve,
+            },
             |status, is_due| async move {
                 if is_due {
                     channel
@@ -189,9 +447,12 @@ async fn handle_archive<T: Sync + Send>(
         .await
         .context("wiping")?;
 
-        sleep(Duration::from_secs(15)).await;
+        // Anything left behind stays reported
+        if summary.is_complete() && summary.not_archived.is_empty() && summary.edited.is_empty() {
+            sleep(Duration::from_secs(15)).await;
 
-        let _ = channel.delete_message(ctx, wiper_status).await;
+            let _ = channel.delete_message(ctx, wiper_status).await;
+        }
     } else {
         let _ = set_dummy_text_component(ctx, &mut latest_message, "Wiping canceled").await;
     }
@@ -200,3 +461,83 @@ async fn handle_archive<T: Sync + Send>(
 
     Ok(())
 }
+
+/// Replies with a report about an archive, as a file when it doesn't fit in
+/// a message
+pub(crate) async fn send_report<T: Send + Sync>(
+    ctx: Context<'_, T>,
+    archive_name: &str,
+    report: String,
+) -> Result<()> {
+    let text = format!("`{archive_name}`\n{report}");
+    let reply = if text.len() <= 2000 {
+        CreateReply::default().content(text)
+    } else {
+        CreateReply::default()
+            .content(format!("Report for `{archive_name}` is attached"))
+            .attachment(CreateAttachment::bytes(text, "report.txt"))
+    };
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+pub(crate) async fn sign_file(
+    key: &SigningKey,
+    path: &std::path::Path,
+    settings: &ArchiveSettings,
+) -> Result<NamedTempFile> {
+    let key = key.clone();
+    let path = path.to_path_buf();
+    let settings = settings.clone();
+    tokio::task::spawn_blocking(move || sign_archive(&path, &key, &settings))
+        .await?
+        .context("signing archive")
+}
+
+/// Encrypts `file` when the guild has a key configured, returning the file to
+/// upload and its name. Encrypted archives can't be browsed, so they must not
+/// be hosted
+pub(crate) async fn encrypt_for_upload(
+    encryption: Option<&EncryptionConfig>,
+    file: NamedTempFile,
+    filename: String,
+    settings: &ArchiveSettings,
+) -> Result<(NamedTempFile, String)> {
+    match encryption {
+        Some(encryption) => Ok((
+            encrypt_archive(encryption, file.path(), settings).await?,
+            encryption.encrypted_filename(&filename),
+        )),
+        None => Ok((file, filename)),
+    }
+}
+
+async fn encrypt_archive(
+    encryption: &EncryptionConfig,
+    path: &std::path::Path,
+    settings: &ArchiveSettings,
+) -> Result<NamedTempFile> {
+    let encryption = encryption.clone();
+    let path = path.to_path_buf();
+    let settings = settings.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut file = NamedTempFile::new()?;
+        encryption.encrypt(&path, &settings, file.as_file_mut())?;
+        Ok::<_, Error>(file)
+    })
+    .await?
+    .context("encrypting archive")
+}
+
+async fn encrypt_split(
+    encryption: &EncryptionConfig,
+    mut split: SplitArchive,
+    settings: &ArchiveSettings,
+) -> Result<SplitArchive> {
+    for (part, info) in split.parts.iter_mut().zip(&mut split.manifest.parts) {
+        part.file = encrypt_archive(encryption, part.file.path(), settings).await?;
+        part.filename = encryption.encrypted_filename(&part.filename);
+        info.filename = part.filename.clone();
+    }
+    Ok(split)
+}
@@ -0,0 +1,165 @@
+//! Reading of finished archives, for steps that post-process them
+
+use anyhow::{Context, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_json::{Map, Value};
+use std::io::Read;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use utils::encryption::{decrypt_age, is_age_encrypted, DecryptionKeys};
+use zip::read::ZipFile;
+use zip::result::ZipResult;
+use zip::ZipArchive;
+
+pub const VIEWER_FILE: &str = "archive.html";
+pub const MESSAGES_FILE: &str = "messages.jsonp";
+pub const METADATA_FILE: &str = "metadata.jsonp";
+pub const ASSETS_PREFIX: &str = "assets/";
+
+/// Splits the contents of `messages.jsonp` into message records, one JSON
+/// object each
+pub fn parse_messages(text: &str) -> Vec<&str> {
+    let text = text.trim();
+    let text = text.strip_prefix("jsonp_parse([").unwrap_or(text);
+    let text = text.strip_suffix("])").unwrap_or(text);
+    text.lines()
+        .map(|line| line.trim().trim_end_matches(','))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Inverse of [parse_messages]
+pub fn write_messages<'a>(records: impl IntoIterator<Item = &'a str>) -> String {
+    let records = records.into_iter().collect::<Vec<_>>();
+    format!("jsonp_parse([\n{}\n])", records.join(",\n"))
+}
+
+/// Extracts the JSON from the contents of `metadata.jsonp`
+pub fn parse_metadata(text: &str) -> &str {
+    let text = text.trim();
+    let text = text.strip_prefix("jsonp_metadata(").unwrap_or(text);
+    text.strip_suffix(')').unwrap_or(text)
+}
+
+/// Collects every string of a record that points into the assets directory
+pub fn referenced_assets(value: &Value, assets: &mut FxHashSet<String>) {
+    match value {
+        Value::String(text) if text.starts_with(ASSETS_PREFIX) => {
+            assets.insert(text.clone());
+        }
+        Value::Array(values) => values.iter().for_each(|e| referenced_assets(e, assets)),
+        Value::Object(values) => values.values().for_each(|e| referenced_assets(e, assets)),
+        _ => {}
+    }
+}
+
+/// Calls `visit` with every attachment list in the record, including those
+/// of referenced and forwarded messages
+pub fn visit_attachments(value: &mut Value, visit: &mut impl FnMut(&mut Map<String, Value>)) {
+    match value {
+        Value::Object(object) => {
+            if object.get("attachments").is_some_and(Value::is_array) {
+                visit(object);
+            }
+            object
+                .values_mut()
+                .for_each(|e| visit_attachments(e, visit));
+        }
+        Value::Array(values) => values.iter_mut().for_each(|e| visit_attachments(e, visit)),
+        _ => {}
+    }
+}
+
+pub fn attachment_objects(
+    parent: &mut Map<String, Value>,
+) -> impl Iterator<Item = &mut Map<String, Value>> {
+    parent
+        .get_mut("attachments")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+/// Finished archive zip, opened for reading
+pub struct ArchiveReader {
+    pub zip: ZipArchive<std::fs::File>,
+    /// Passphrase of encrypted entries
+    passphrase: Option<String>,
+    /// Decrypted copy of an age-encrypted archive, removed with the reader
+    _decrypted: Option<NamedTempFile>,
+}
+
+impl ArchiveReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, &DecryptionKeys::default())
+    }
+
+    /// Opens an archive that may be encrypted, with the keys to read it
+    pub fn open_with(path: &Path, keys: &DecryptionKeys) -> Result<Self> {
+        let decrypted = match is_age_encrypted(path).context("opening archive")? {
+            true => {
+                let mut file = NamedTempFile::new()?;
+                decrypt_age(path, &keys.identities, file.as_file_mut())?;
+                Some(file)
+            }
+            false => None,
+        };
+        let path = decrypted.as_ref().map_or(path, |e| e.path());
+        let file = std::fs::File::open(path).context("opening archive")?;
+        Ok(ArchiveReader {
+            zip: ZipArchive::new(file).context("reading archive")?,
+            passphrase: keys.passphrase.clone(),
+            _decrypted: decrypted,
+        })
+    }
+
+    /// Whether encrypted entries can be read
+    pub fn has_passphrase(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    /// Number of entries encrypted with a passphrase
+    pub fn encrypted_entries(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for i in 0..self.zip.len() {
+            if self.zip.by_index_raw(i)?.encrypted() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Opens entry `index`, decrypted with the passphrase when there is one
+    pub fn entry(&mut self, index: usize) -> ZipResult<ZipFile<'_, std::fs::File>> {
+        match &self.passphrase {
+            Some(passphrase) => self.zip.by_index_decrypt(index, passphrase.as_bytes()),
+            None => self.zip.by_index(index),
+        }
+    }
+
+    pub fn read_string(&mut self, name: &str) -> Result<String> {
+        let index = self
+            .zip
+            .index_for_name(name)
+            .with_context(|| format!("opening {name} in the archive"))?;
+        let mut text = String::new();
+        self.entry(index)
+            .with_context(|| format!("opening {name} in the archive"))?
+            .read_to_string(&mut text)
+            .with_context(|| format!("reading {name} from the archive"))?;
+        Ok(text)
+    }
+
+    /// Compressed sizes of all files in the archive
+    pub fn compressed_sizes(&mut self) -> Result<FxHashMap<String, u64>> {
+        let mut sizes = FxHashMap::default();
+        for i in 0..self.zip.len() {
+            let entry = self.zip.by_index_raw(i)?;
+            if entry.is_file() {
+                sizes.insert(entry.name().to_string(), entry.compressed_size());
+            }
+        }
+        Ok(sizes)
+    }
+}
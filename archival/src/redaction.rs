@@ -0,0 +1,270 @@
+//! Pseudonymisation and redaction of archived messages, for archives that
+//! are shared outside the guild
+
+use anyhow::{Context, Result};
+use image::{ImageFormat, Rgb, RgbImage};
+use lazy_regex::{regex, Regex};
+use poise::serenity_prelude::{
+    ActionRow, ActionRowComponent, Embed, Message, PartialMember, Poll, User, UserId,
+};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::path::Path;
+
+const REDACTED: &str = "[redacted]";
+const AVATAR_GRID: u32 = 5;
+const AVATAR_CELL: u32 = 16;
+const AVATAR_PADDING: u32 = 8;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedactionOptions {
+    /// Replace user IDs, names and avatars with stable pseudonyms such as
+    /// "User 7", including mentions in message content
+    pub pseudonymize: bool,
+    /// Leave attachments out of the archive
+    pub strip_attachments: bool,
+    /// Redact email addresses and Discord tokens in message text
+    pub redact_secrets: bool,
+    /// Additional regular expressions whose matches are redacted
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactionOptions {
+    fn default() -> Self {
+        RedactionOptions {
+            pseudonymize: true,
+            strip_attachments: false,
+            redact_secrets: true,
+            patterns: vec![],
+        }
+    }
+}
+
+/// Applies [RedactionOptions] to messages, keeping pseudonyms consistent
+/// across the whole archive
+#[derive(Debug)]
+pub(crate) struct Redactor {
+    options: RedactionOptions,
+    patterns: Vec<Regex>,
+    pseudonyms: FxHashMap<UserId, UserId>,
+}
+
+impl Redactor {
+    pub fn new(options: RedactionOptions) -> Result<Self> {
+        let mut patterns = vec![];
+        if options.redact_secrets {
+            patterns.push(Regex::clone(regex!(r"[\w.+-]+@[\w-]+(\.[\w-]+)+")));
+            patterns.push(Regex::clone(regex!(r"[\w-]{24,}\.[\w-]{6}\.[\w-]{27,}")));
+        }
+        for pattern in &options.patterns {
+            patterns.push(
+                Regex::new(pattern)
+                    .with_context(|| format!("compiling redaction pattern {pattern}"))?,
+            );
+        }
+        Ok(Redactor {
+            options,
+            patterns,
+            pseudonyms: Default::default(),
+        })
+    }
+
+    pub fn pseudonymizes(&self) -> bool {
+        self.options.pseudonymize
+    }
+
+    /// Pseudonymous ID of a user, numbered in order of first appearance
+    pub fn user_id(&mut self, id: UserId) -> UserId {
+        if !self.options.pseudonymize {
+            return id;
+        }
+        let next = UserId::new(self.pseudonyms.len() as u64 + 1);
+        *self.pseudonyms.entry(id).or_insert(next)
+    }
+
+    pub fn user_name(&mut self, id: UserId, name: String) -> String {
+        match self.options.pseudonymize {
+            true => pseudonym_name(self.user_id(id)),
+            false => name,
+        }
+    }
+
+    pub fn user(&mut self, user: &mut User) {
+        if !self.options.pseudonymize {
+            return;
+        }
+        let mut pseudonym = User::default();
+        pseudonym.id = self.user_id(user.id);
+        pseudonym.name = pseudonym_name(pseudonym.id);
+        pseudonym.global_name = Some(pseudonym.name.clone());
+        pseudonym.bot = user.bot;
+        *user = pseudonym;
+    }
+
+    fn member(&mut self, member: &mut PartialMember) {
+        if !self.options.pseudonymize {
+            return;
+        }
+        member.nick = None;
+        member.avatar = None;
+        member.banner = None;
+        if let Some(user) = &mut member.user {
+            self.user(user);
+        }
+    }
+
+    /// Rewrites user mentions and redacts matches of the patterns
+    pub fn text(&mut self, text: &str) -> String {
+        let mut text = if self.options.pseudonymize {
+            regex!(r"<@!?(\d+)>")
+                .replace_all(text, |captures: &lazy_regex::Captures| {
+                    match captures[1].parse::<u64>().ok().filter(|id| *id != 0) {
+                        Some(id) => format!("<@{}>", self.user_id(UserId::new(id))),
+                        None => captures[0].to_string(),
+                    }
+                })
+                .into_owned()
+        } else {
+            text.to_string()
+        };
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, REDACTED).into_owned();
+        }
+        text
+    }
+
+    fn optional_text(&mut self, text: &mut Option<String>) {
+        if let Some(text) = text {
+            *text = self.text(text);
+        }
+    }
+
+    fn poll(&mut self, poll: &mut Poll) {
+        self.optional_text(&mut poll.question.text);
+        for answer in &mut poll.answers {
+            self.optional_text(&mut answer.poll_media.text);
+        }
+    }
+
+    fn components(&mut self, rows: &mut [ActionRow]) {
+        for component in rows.iter_mut().flat_map(|row| &mut row.components) {
+            match component {
+                ActionRowComponent::Button(button) => self.optional_text(&mut button.label),
+                ActionRowComponent::SelectMenu(menu) => {
+                    self.optional_text(&mut menu.placeholder);
+                    for option in &mut menu.options {
+                        option.label = self.text(&option.label);
+                        self.optional_text(&mut option.description);
+                    }
+                }
+                ActionRowComponent::InputText(input) => {
+                    self.optional_text(&mut input.label);
+                    self.optional_text(&mut input.placeholder);
+                    self.optional_text(&mut input.value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn embed(&mut self, embed: &mut Embed) {
+        for text in [&mut embed.title, &mut embed.description]
+            .into_iter()
+            .flatten()
+        {
+            *text = self.text(text);
+        }
+        for field in &mut embed.fields {
+            field.name = self.text(&field.name);
+            field.value = self.text(&field.value);
+        }
+        if let Some(footer) = &mut embed.footer {
+            footer.text = self.text(&footer.text);
+        }
+        if let Some(author) = &mut embed.author {
+            author.name = self.text(&author.name);
+        }
+    }
+
+    /// Redacts the message in place, before anything of it is downloaded
+    /// or serialized
+    pub fn message(&mut self, message: &mut Message) {
+        self.user(&mut message.author);
+        message.mentions.iter_mut().for_each(|user| self.user(user));
+        if let Some(member) = &mut message.member {
+            self.member(member);
+        }
+        #[allow(deprecated)]
+        if let Some(interaction) = &mut message.interaction {
+            self.user(&mut interaction.user);
+            if let Some(member) = &mut interaction.member {
+                self.member(member);
+            }
+        }
+        if self.options.pseudonymize {
+            message.interaction_metadata = None;
+        }
+        if let Some(owner) = message.thread.as_mut().and_then(|e| e.owner_id.as_mut()) {
+            *owner = self.user_id(*owner);
+        }
+
+        message.content = self.text(&message.content);
+        message.embeds.iter_mut().for_each(|e| self.embed(e));
+        if let Some(poll) = &mut message.poll {
+            self.poll(poll);
+        }
+        self.components(&mut message.components);
+        for snapshot in &mut message.message_snapshots {
+            snapshot.content = self.text(&snapshot.content);
+            snapshot
+                .mentions
+                .iter_mut()
+                .for_each(|user| self.user(user));
+            snapshot.embeds.iter_mut().for_each(|e| self.embed(e));
+            self.components(&mut snapshot.components);
+            if self.options.strip_attachments {
+                snapshot.attachments.clear();
+            }
+        }
+        if self.options.strip_attachments {
+            message.attachments.clear();
+        }
+    }
+}
+
+pub fn pseudonym_name(id: UserId) -> String {
+    format!("User {id}")
+}
+
+/// Writes a symmetric identicon for the pseudonymous user
+pub fn write_generated_avatar(id: UserId, path: &Path) -> Result<()> {
+    // Spread consecutive IDs over distinct patterns and colors
+    let hash = id.get().wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(17);
+    let color = Rgb([
+        64 + (hash >> 40 & 0x7f) as u8,
+        64 + (hash >> 48 & 0x7f) as u8,
+        64 + (hash >> 56 & 0x7f) as u8,
+    ]);
+    let size = AVATAR_GRID * AVATAR_CELL + AVATAR_PADDING * 2;
+    let mut image = RgbImage::from_pixel(size, size, Rgb([240, 240, 240]));
+    for row in 0..AVATAR_GRID {
+        for column in 0..AVATAR_GRID.div_ceil(2) {
+            if hash >> (row * 3 + column) & 1 == 0 {
+                continue;
+            }
+            for cell_column in [column, AVATAR_GRID - 1 - column] {
+                let x0 = AVATAR_PADDING + cell_column * AVATAR_CELL;
+                let y0 = AVATAR_PADDING + row * AVATAR_CELL;
+                for y in y0..y0 + AVATAR_CELL {
+                    for x in x0..x0 + AVATAR_CELL {
+                        image.put_pixel(x, y, color);
+                    }
+                }
+            }
+        }
+    }
+    image
+        .save_with_format(path, ImageFormat::Png)
+        .context("writing generated avatar")
+}
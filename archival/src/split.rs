@@ -0,0 +1,344 @@
+//! Splitting of archives that are too large for a Discord attachment into
+//! several self-contained parts
+
+use crate::records::{
+    parse_messages, parse_metadata, referenced_assets, write_messages, ArchiveReader,
+    MESSAGES_FILE, METADATA_FILE, VIEWER_FILE,
+};
+use anyhow::{bail, Context, Result};
+use poise::serenity_prelude::{ChannelId, CreateAttachment, CreateMessage, Message, Timestamp};
+use rustc_hash::FxHashSet;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use utils::zip::ArchiveSettings;
+use zip::ZipWriter;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Room reserved for zip headers of a single file
+const ENTRY_OVERHEAD: u64 = 512;
+/// Room reserved for the manifest and the central directory
+const PART_OVERHEAD: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PartInfo {
+    pub filename: String,
+    pub message_count: usize,
+    pub first_message: Option<String>,
+    pub last_message: Option<String>,
+    pub start: Option<Timestamp>,
+    pub end: Option<Timestamp>,
+}
+
+/// Description of all parts, included in every part and posted alongside them
+#[derive(Debug, Clone, Serialize)]
+pub struct SplitManifest {
+    pub archive: String,
+    pub total_messages: usize,
+    pub parts: Vec<PartInfo>,
+}
+
+pub struct ArchivePart {
+    pub file: NamedTempFile,
+    pub filename: String,
+}
+
+pub struct SplitArchive {
+    pub parts: Vec<ArchivePart>,
+    pub manifest: SplitManifest,
+}
+
+#[derive(Default)]
+struct Chunk {
+    records: Vec<usize>,
+    assets: FxHashSet<String>,
+    size: u64,
+}
+
+fn record_info(record: &Value) -> (Option<String>, Option<Timestamp>) {
+    let id = record["id"]
+        .as_str()
+        .map(str::to_string)
+        .or_else(|| record["id"].as_u64().map(|e| e.to_string()));
+    let timestamp = serde_json::from_value(record["timestamp"].clone()).ok();
+    (id, timestamp)
+}
+
+/// Splits the archive at `path` into parts no larger than `size_limit`,
+/// keeping the order of messages and giving every part the assets it uses
+pub fn split_archive(
+    path: &Path,
+    filename: &str,
+    size_limit: u64,
+    settings: &ArchiveSettings,
+) -> Result<SplitArchive> {
+    let mut archive = ArchiveReader::open(path)?;
+    let sizes = archive.compressed_sizes()?;
+    let messages = archive.read_string(MESSAGES_FILE)?;
+    let metadata = archive.read_string(METADATA_FILE).ok();
+
+    let records = parse_messages(&messages)
+        .into_iter()
+        .map(|text| Ok((text, serde_json::from_str::<Value>(text)?)))
+        .collect::<Result<Vec<_>>>()
+        .context("parsing archived messages")?;
+
+    // Files that every part gets
+    let mut common = FxHashSet::default();
+    if let Some(metadata) = &metadata {
+        let metadata: Value =
+            serde_json::from_str(parse_metadata(metadata)).context("parsing metadata")?;
+        referenced_assets(&metadata, &mut common);
+        common.insert(METADATA_FILE.to_string());
+    }
+    common.insert(VIEWER_FILE.to_string());
+    common.retain(|name| sizes.contains_key(name));
+    let base_size = PART_OVERHEAD
+        + common
+            .iter()
+            .map(|name| sizes[name] + ENTRY_OVERHEAD)
+            .sum::<u64>();
+
+    let asset_size = |chunk: &Chunk, assets: &FxHashSet<String>| {
+        assets
+            .iter()
+            .filter(|name| !chunk.assets.contains(*name))
+            .map(|name| sizes[name] + ENTRY_OVERHEAD)
+            .sum::<u64>()
+    };
+
+    let mut chunks = vec![];
+    let mut chunk = Chunk::default();
+    for (i, (text, record)) in records.iter().enumerate() {
+        let mut assets = FxHashSet::default();
+        referenced_assets(record, &mut assets);
+        assets.retain(|name| sizes.contains_key(name) && !common.contains(name));
+
+        let mut added = text.len() as u64 + asset_size(&chunk, &assets);
+        if !chunk.records.is_empty() && base_size + chunk.size + added > size_limit {
+            chunks.push(std::mem::take(&mut chunk));
+            added = text.len() as u64 + asset_size(&chunk, &assets);
+        }
+        if chunk.records.is_empty() && base_size + added > size_limit {
+            let (id, _) = record_info(record);
+            bail!(
+                "Message {} doesn't fit into a single part",
+                id.unwrap_or_default()
+            );
+        }
+        chunk.records.push(i);
+        chunk.assets.extend(assets);
+        chunk.size += added;
+    }
+    if !chunk.records.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+
+    let base_name = filename.strip_suffix(".zip").unwrap_or(filename);
+    let total = chunks.len();
+    let manifest = SplitManifest {
+        archive: filename.to_string(),
+        total_messages: records.len(),
+        parts: chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let infos = chunk
+                    .records
+                    .iter()
+                    .map(|e| record_info(&records[*e].1))
+                    .collect::<Vec<_>>();
+                let timestamps = infos.iter().filter_map(|(_, time)| *time);
+                PartInfo {
+                    filename: format!("{base_name} (part {} of {total}).zip", i + 1),
+                    message_count: chunk.records.len(),
+                    first_message: infos.first().and_then(|(id, _)| id.clone()),
+                    last_message: infos.last().and_then(|(id, _)| id.clone()),
+                    start: timestamps.clone().min(),
+                    end: timestamps.max(),
+                }
+            })
+            .collect(),
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+
+    let options = settings.zip_file_options();
+    let mut parts = vec![];
+    for (chunk, info) in chunks.iter().zip(&manifest.parts) {
+        let mut file = NamedTempFile::new().context("creating archive part")?;
+        let mut zip = ZipWriter::new(file.as_file_mut());
+
+        let mut names = common.iter().chain(&chunk.assets).collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            zip.raw_copy_file(archive.zip.by_name(name)?)
+                .with_context(|| format!("copying {name}"))?;
+        }
+        zip.start_file(MESSAGES_FILE, options)?;
+        zip.write_all(write_messages(chunk.records.iter().map(|e| records[*e].0)).as_bytes())?;
+        zip.start_file(MANIFEST_FILE, options)?;
+        zip.write_all(&manifest_json)?;
+        zip.finish()?;
+
+        parts.push(ArchivePart {
+            file,
+            filename: info.filename.clone(),
+        });
+    }
+
+    Ok(SplitArchive { parts, manifest })
+}
+
+/// Posts every part as its own attachment, followed by the manifest
+pub async fn upload_split_archive<T: Sync + Send>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    channel: ChannelId,
+    split: SplitArchive,
+    message_prefix: String,
+) -> Result<Message> {
+    let total = split.parts.len();
+    for (i, part) in split.parts.iter().enumerate() {
+        let file = tokio::fs::File::open(part.file.path()).await?;
+        channel
+            .send_files(
+                ctx.serenity_context(),
+                [CreateAttachment::file(&file, part.filename.as_str()).await?],
+                CreateMessage::new().content(format!("Part {} of {total}", i + 1)),
+            )
+            .await
+            .with_context(|| format!("uploading archive part {}", i + 1))?;
+    }
+
+    let base_name = split
+        .manifest
+        .archive
+        .strip_suffix(".zip")
+        .unwrap_or(&split.manifest.archive);
+    let manifest = CreateAttachment::bytes(
+        serde_json::to_vec_pretty(&split.manifest)?,
+        format!("{base_name} (manifest).json"),
+    );
+    channel
+        .send_files(
+            ctx.serenity_context(),
+            [manifest],
+            CreateMessage::new().content(format!(
+                "{message_prefix}\nArchive was too large and got split into {}",
+                pluralizer::pluralize("parts", total as isize, true)
+            )),
+        )
+        .await
+        .context("uploading archive manifest")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::zip::{write_directory, ZipCompression};
+    use zip::ZipArchive;
+
+    const MESSAGE_COUNT: usize = 40;
+    const ASSET_SIZE: usize = 10 * 1024;
+    const SIZE_LIMIT: u64 = 128 * 1024;
+
+    fn record(id: usize) -> String {
+        serde_json::json!({
+            "id": id.to_string(),
+            "timestamp": format!("2024-01-01T00:00:{:02}Z", id),
+            "attachments": [{ "url": format!("assets/{id}.bin") }],
+            "author_avatar::processed": "assets/avatar.png",
+        })
+        .to_string()
+    }
+
+    /// Archive whose messages each have an attachment, and share an avatar
+    fn generated_archive(settings: &ArchiveSettings) -> NamedTempFile {
+        let dir = tempfile::tempdir().unwrap();
+        let assets = dir.path().join("assets");
+        std::fs::create_dir(&assets).unwrap();
+        let records = (1..=MESSAGE_COUNT).map(record).collect::<Vec<_>>();
+        for id in 1..=MESSAGE_COUNT {
+            std::fs::write(assets.join(format!("{id}.bin")), vec![id as u8; ASSET_SIZE]).unwrap();
+        }
+        std::fs::write(assets.join("avatar.png"), "avatar").unwrap();
+        std::fs::write(assets.join("guild_icon.png"), "icon").unwrap();
+        std::fs::write(dir.path().join(VIEWER_FILE), "<html></html>").unwrap();
+        std::fs::write(
+            dir.path().join(METADATA_FILE),
+            r#"jsonp_metadata({"guild": {"icon": "assets/guild_icon.png"}})"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join(MESSAGES_FILE),
+            write_messages(records.iter().map(String::as_str)),
+        )
+        .unwrap();
+        let mut archive = NamedTempFile::new().unwrap();
+        write_directory(dir.path(), archive.as_file_mut(), settings).unwrap();
+        archive
+    }
+
+    fn read(zip: &mut ZipArchive<std::fs::File>, name: &str) -> String {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name(name).unwrap(), &mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn parts_are_self_contained_and_ordered() {
+        let settings = ArchiveSettings {
+            compression: ZipCompression::Stored,
+            ..Default::default()
+        };
+        let archive = generated_archive(&settings);
+        assert!(archive.as_file().metadata().unwrap().len() > SIZE_LIMIT);
+        let split = split_archive(archive.path(), "general.zip", SIZE_LIMIT, &settings).unwrap();
+        let manifest = serde_json::to_value(&split.manifest).unwrap();
+        assert!(split.parts.len() > 2);
+        assert_eq!(split.parts.len(), split.manifest.parts.len());
+        assert_eq!(split.manifest.total_messages, MESSAGE_COUNT);
+
+        let mut ids = vec![];
+        for (i, (part, info)) in split.parts.iter().zip(&split.manifest.parts).enumerate() {
+            assert!(part.file.as_file().metadata().unwrap().len() <= SIZE_LIMIT);
+            assert_eq!(part.filename, info.filename);
+            assert_eq!(
+                info.filename,
+                format!("general (part {} of {}).zip", i + 1, split.parts.len())
+            );
+
+            let mut zip = ZipArchive::new(part.file.reopen().unwrap()).unwrap();
+            let part_manifest: Value =
+                serde_json::from_str(&read(&mut zip, MANIFEST_FILE)).unwrap();
+            assert_eq!(part_manifest, manifest);
+            for name in [VIEWER_FILE, METADATA_FILE, "assets/guild_icon.png"] {
+                assert!(zip.index_for_name(name).is_some(), "{name} missing");
+            }
+
+            let messages = read(&mut zip, MESSAGES_FILE);
+            let records = parse_messages(&messages);
+            assert_eq!(records.len(), info.message_count);
+            let mut assets = FxHashSet::default();
+            for record in records {
+                let record: Value = serde_json::from_str(record).unwrap();
+                referenced_assets(&record, &mut assets);
+                ids.push(record["id"].as_str().unwrap().to_string());
+            }
+            assert_eq!(
+                info.first_message.as_ref(),
+                ids.get(ids.len() - info.message_count)
+            );
+            assert_eq!(info.last_message.as_ref(), ids.last());
+            for asset in assets {
+                assert!(zip.index_for_name(&asset).is_some(), "{asset} missing");
+            }
+        }
+        let expected = (1..=MESSAGE_COUNT)
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, expected);
+    }
+}
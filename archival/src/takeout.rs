@@ -0,0 +1,288 @@
+//! Export of everything a member posted across the guild
+
+use crate::archival::{archive_messages, ArchiveData, ArchiveOptions};
+use crate::config::ArchivalData;
+use crate::{encrypt_for_upload, sign_file, Context, ENCRYPTED_NOTE};
+use anyhow::{Context as AnyhowContext, Result};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use poise::serenity_prelude::{self as serenity, ChannelId, Message, PremiumTier, Timestamp, User};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::time::sleep;
+use utils::command_handler_wrapper;
+use utils::into_edit::IntoEdit;
+use utils::message_filter::MessageFilter;
+use utils::messages_iter::{guild_message_channels, smart_messages_iter, MessagesRange};
+use utils::web_files::messaged::{premium_tier_size_limit, upload_file_and_message, UploadOptions};
+use utils::zip::ArchiveFormat;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+#[macro_export]
+macro_rules! takeout_command {
+    ($name:ident, $data:ty) => {
+        /// Export every message of a member across all channels and threads
+        #[poise::command(
+            slash_command,
+            prefix_command,
+            required_permissions = "MANAGE_MESSAGES",
+            default_member_permissions = "MANAGE_MESSAGES",
+            required_bot_permissions = "READ_MESSAGE_HISTORY",
+            guild_only
+        )]
+        async fn $name(
+            ctx: poise::Context<'_, $data, anyhow::Error>,
+            #[description = "Member whose messages are exported"]
+            user: poise::serenity_prelude::User,
+            #[description = "Send the export to the member by DM instead of this channel"]
+            dm: Option<bool>,
+        ) -> Result<()> {
+            archival::takeout::takeout(ctx, user, dm.unwrap_or(false)).await
+        }
+    };
+}
+
+#[derive(Debug, Default)]
+struct TakeoutProgress {
+    channel: usize,
+    scanned: usize,
+    status: String,
+    /// Channels that couldn't be read, or only partially
+    unreadable: Vec<ChannelId>,
+}
+
+impl TakeoutProgress {
+    fn describe(&self, user: &User, channels: &[ChannelId]) -> String {
+        let mut text = format!(
+            "Exporting messages of <@{}>\nChannel {} of {}: <#{}>\nMessages scanned: {}",
+            user.id,
+            self.channel + 1,
+            channels.len(),
+            channels.get(self.channel).copied().unwrap_or_default(),
+            self.scanned
+        );
+        if !self.status.is_empty() {
+            text.push('\n');
+            text.push_str(&self.status);
+        }
+        text
+    }
+}
+
+/// Messages of the `index`th channel, ending at the first error so
+/// unreadable channels are skipped
+fn channel_messages<'a, T: Send + Sync>(
+    ctx: Context<'a, T>,
+    index: usize,
+    channel: ChannelId,
+    progress: Arc<Mutex<TakeoutProgress>>,
+) -> BoxStream<'a, Result<Message>> {
+    let started = progress.clone();
+    // Streams are lazy, so this marks the channel once it is reached
+    let start = futures::stream::once(async move {
+        started.lock().unwrap().channel = index;
+    })
+    .filter_map(|()| futures::future::ready(None));
+    let messages = smart_messages_iter(ctx, channel, MessagesRange::unbounded())
+        .inspect(move |result: &serenity::Result<Message>| {
+            let mut progress = progress.lock().unwrap();
+            match result {
+                Ok(_) => progress.scanned += 1,
+                Err(_) => progress.unreadable.push(channel),
+            }
+        })
+        .take_while(|result: &serenity::Result<Message>| futures::future::ready(result.is_ok()))
+        .map_err(anyhow::Error::from);
+    start.chain(messages).boxed()
+}
+
+pub async fn takeout<T: ArchivalData>(ctx: Context<'_, T>, user: User, dm: bool) -> Result<()> {
+    command_handler_wrapper!(handle_takeout(ctx, user, dm))
+}
+
+/// Archive of every message of a member in a set of channels
+pub(crate) struct UserExport {
+    pub file: NamedTempFile,
+    pub filename: String,
+    /// Whether the archive is encrypted with the guild's key
+    pub encrypted: bool,
+    /// Channels that couldn't be read, or only partially
+    pub unreadable: Vec<ChannelId>,
+}
+
+impl UserExport {
+    /// Notes about the encryption and the unreadable channels, if any
+    pub fn notes(&self) -> Option<String> {
+        let mut notes = vec![];
+        if self.encrypted {
+            notes.push(ENCRYPTED_NOTE.to_string());
+        }
+        if !self.unreadable.is_empty() {
+            let channels = self
+                .unreadable
+                .iter()
+                .map(|e| format!("<#{e}>"))
+                .collect::<Vec<_>>();
+            notes.push(format!(
+                "These channels could not be read completely: {}",
+                channels.join(", ")
+            ));
+        }
+        (!notes.is_empty()).then(|| notes.join("\n"))
+    }
+}
+
+/// Archives the messages of `user` in `channels`, reporting progress by
+/// editing `reply`. The archive is signed and encrypted when the guild has
+/// them configured
+pub(crate) async fn export_user_messages<T: ArchivalData>(
+    ctx: Context<'_, T>,
+    user: &User,
+    channels: &[ChannelId],
+    reply: &Message,
+) -> Result<UserExport> {
+    let config = ctx.data().archival_config();
+    let progress = Arc::new(Mutex::new(TakeoutProgress::default()));
+    let messages = futures::stream::iter(
+        channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| channel_messages(ctx, i, *channel, progress.clone()))
+            .collect::<Vec<_>>(),
+    )
+    .flatten();
+    let filter = MessageFilter {
+        authors: vec![user.id],
+        ..Default::default()
+    };
+    let options = ArchiveOptions {
+        images: config.images.clone(),
+        archive: config.archive.clone(),
+        ..Default::default()
+    };
+
+    let done = AtomicBool::new(false);
+    let archival = async {
+        let result = archive_messages(ctx, filter.apply(messages), options, |status| {
+            progress.lock().unwrap().status = status;
+            async { Ok(()) }
+        })
+        .await;
+        done.store(true, Ordering::Relaxed);
+        result
+    };
+    let ticker = async {
+        while !done.load(Ordering::Relaxed) {
+            sleep(PROGRESS_INTERVAL).await;
+            let text = progress.lock().unwrap().describe(user, channels);
+            let _ = reply
+                .channel_id
+                .edit_message(ctx, reply.id, text.into_edit())
+                .await;
+        }
+    };
+    let (archived, ()) = futures::join!(archival, ticker);
+    let ArchiveData { file, .. } = archived.context("archiving messages")?;
+
+    let file = match config.signing.as_ref() {
+        Some(signing) if config.archive.format == ArchiveFormat::Zip => {
+            sign_file(&signing.signing_key()?, file.path(), &config.archive).await?
+        }
+        _ => file,
+    };
+
+    let filename = format!(
+        "{} - takeout {} ({}).{}",
+        Timestamp::now().date_naive().format("%Y-%m-%d"),
+        user.name,
+        user.id,
+        config.archive.extension()
+    );
+    let encryption = config.encryption(ctx.guild_id());
+    let (file, filename) = encrypt_for_upload(encryption, file, filename, &config.archive).await?;
+    let unreadable = std::mem::take(&mut progress.lock().unwrap().unreadable);
+    Ok(UserExport {
+        file,
+        filename,
+        encrypted: encryption.is_some(),
+        unreadable,
+    })
+}
+
+async fn handle_takeout<T: ArchivalData>(ctx: Context<'_, T>, user: User, dm: bool) -> Result<()> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow::anyhow!("Takeout is only available in guilds"))?;
+    let config = ctx.data().archival_config();
+
+    let reply = ctx.say("Listing channels").await?.into_message().await?;
+    let listing = guild_message_channels(ctx, guild_id)
+        .await
+        .context("listing channels")?;
+    let channels = listing.channels.iter().map(|e| e.id).collect::<Vec<_>>();
+
+    let mut export = export_user_messages(ctx, &user, &channels, &reply).await?;
+    export.unreadable.extend(listing.incomplete);
+    let mut prefix = format!(
+        "Export of all messages of <@{}> in {}",
+        user.id,
+        pluralizer::pluralize("channels", channels.len() as isize, true)
+    );
+    if let Some(note) = export.notes() {
+        prefix.push('\n');
+        prefix.push_str(&note);
+    }
+    let UserExport { file, filename, .. } = export;
+
+    if dm {
+        let channel = user
+            .create_dm_channel(ctx)
+            .await
+            .context("opening DM channel")?;
+        upload_file_and_message(
+            ctx,
+            channel.id,
+            file.as_file(),
+            file.path(),
+            filename,
+            prefix,
+            UploadOptions {
+                sinks: &config.sinks,
+                size_limit_override: config
+                    .upload_size_limit
+                    .or(Some(premium_tier_size_limit(PremiumTier::Tier0))),
+            },
+        )
+        .await
+        .context("sending export by DM")?;
+        reply
+            .channel_id
+            .edit_message(
+                ctx,
+                reply.id,
+                format!("Export was sent to <@{}> by DM", user.id).into_edit(),
+            )
+            .await?;
+    } else {
+        upload_file_and_message(
+            ctx,
+            ctx.channel_id(),
+            file.as_file(),
+            file.path(),
+            filename,
+            prefix,
+            UploadOptions {
+                sinks: &config.sinks,
+                size_limit_override: config.upload_size_limit,
+            },
+        )
+        .await?;
+        reply.delete(ctx).await?;
+    }
+
+    file.close()?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use crate::server::ServerConfig;
+use anyhow::{Context, Result};
+use archival::config::ArchivalConfig;
+use serde::Deserialize;
+use std::path::Path;
+use wiper::config::WiperConfig;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub archival: ArchivalConfig,
+    pub wiper: WiperConfig,
+    /// Built-in server for hosting archives, disabled when absent
+    pub server: Option<ServerConfig>,
+}
+
+impl Config {
+    /// Loads the configuration file, falling back to defaults if it doesn't exist
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            println!("No config at {}, using defaults", path.display());
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config at {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing config at {}", path.display()))
+    }
+}
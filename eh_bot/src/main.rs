@@ -1,13 +1,53 @@
+use crate::config::Config;
 use anyhow::Result;
-use archival::archive_command;
-use poise::serenity_prelude::{ClientBuilder, GatewayIntents};
+use archival::config::{ArchivalConfig, ArchivalData};
+use archival::{archive_command, doctor_command, erase_command, takeout_command, verify_command};
+use poise::serenity_prelude::{ClientBuilder, GatewayIntents, GuildId};
 use poise::PrefixFrameworkOptions;
+use utils::web_files::hosting::HostingConfig;
+use wiper::config::{WiperConfig, WiperData};
+use wiper::purge_command;
+use wiper::wiping::PreservationRules;
 
-struct Data {}
+mod config;
+mod server;
+
+struct Data {
+    config: Config,
+}
+
+impl ArchivalData for Data {
+    fn archival_config(&self) -> &ArchivalConfig {
+        &self.config.archival
+    }
+
+    fn archive_hosting(&self) -> Option<&HostingConfig> {
+        self.config.server.as_ref().map(|server| &server.hosting)
+    }
+}
+
+impl WiperData for Data {
+    fn wiper_config(&self) -> &WiperConfig {
+        &self.config.wiper
+    }
+
+    fn preservation_rules(&self, guild_id: Option<GuildId>) -> PreservationRules {
+        self.config
+            .archival
+            .guild(guild_id)
+            .map(|guild| guild.preserve.clone())
+            .unwrap_or_default()
+    }
+}
 
 type Context<'a> = poise::Context<'a, Data, anyhow::Error>;
 
 archive_command!(archive, Data);
+verify_command!(verify, Data);
+doctor_command!(doctor, Data);
+takeout_command!(takeout, Data);
+erase_command!(erase, Data);
+purge_command!(purge, Data);
 
 #[poise::command(prefix_command, owners_only, hide_in_help)]
 async fn register(ctx: Context<'_>) -> Result<()> {
@@ -34,11 +74,29 @@ Type /help command for more info on a command.",
 #[tokio::main]
 async fn main() {
     let token = std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
+    let config_path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".to_string());
+    let config = Config::load(config_path).expect("failed to load config");
+    if let Some(server) = config.server.clone() {
+        tokio::spawn(async move {
+            if let Err(err) = server::run(server).await {
+                println!("Archive server stopped: {err:?}");
+            }
+        });
+    }
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![register(), archive(), help()],
+            commands: vec![
+                register(),
+                archive(),
+                verify(),
+                doctor(),
+                takeout(),
+                erase(),
+                purge(),
+                help(),
+            ],
             prefix_options: PrefixFrameworkOptions {
                 prefix: Some("dh!".to_string()),
                 ..Default::default()
@@ -47,7 +105,7 @@ async fn main() {
         })
         .setup(|_ctx, _ready, _framework| {
             // poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-            Box::pin(async move { Ok(Data {}) })
+            Box::pin(async move { Ok(Data { config }) })
         })
         .build();
 
@@ -0,0 +1,347 @@
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use utils::web_files::hosting::{HostedArchiveInfo, HostingConfig};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    #[serde(flatten)]
+    pub hosting: HostingConfig,
+}
+
+/// Size of the chunks archive entries are streamed in
+const ENTRY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of archives kept open, the least recently used one is closed
+/// when another is opened
+const MAX_OPEN_ARCHIVES: usize = 32;
+
+/// The viewer only needs its own inline scripts and styles, and the files of
+/// the archive. Avatars whose download failed still point to Discord's CDN
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; \
+    script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; \
+    img-src 'self' data: https://cdn.discordapp.com; media-src 'self'; \
+    base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+/// Read handle to an archive that can be cloned cheaply, each clone keeping
+/// its own position in the shared file
+#[derive(Debug, Clone)]
+struct ArchiveFile {
+    file: Arc<File>,
+    position: u64,
+    length: u64,
+}
+
+impl ArchiveFile {
+    fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(ArchiveFile {
+            file: Arc::new(file),
+            position: 0,
+            length,
+        })
+    }
+}
+
+impl Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(&*self.file, buf, self.position)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(&*self.file, buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ArchiveFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek out of bounds")
+        })?;
+        Ok(self.position)
+    }
+}
+
+type Archive = zip::ZipArchive<ArchiveFile>;
+
+struct AppData {
+    hosting: HostingConfig,
+    /// Opened archives by ID with when they were last used, so the central
+    /// directory is only read once
+    archives: Mutex<FxHashMap<String, (Archive, Instant)>>,
+}
+
+type AppState = Arc<AppData>;
+
+impl AppData {
+    fn archive(&self, id: &str) -> Result<Archive> {
+        if let Some((archive, used)) = self.archives.lock().unwrap().get_mut(id) {
+            *used = Instant::now();
+            return Ok(archive.clone());
+        }
+        let archive = zip::ZipArchive::new(ArchiveFile::open(&self.hosting.archive_path(id))?)?;
+        let mut archives = self.archives.lock().unwrap();
+        if archives.len() >= MAX_OPEN_ARCHIVES {
+            let oldest = archives
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                archives.remove(&oldest);
+            }
+        }
+        archives.insert(id.to_string(), (archive.clone(), Instant::now()));
+        Ok(archive)
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+    match extension.to_lowercase().as_str() {
+        "html" => "text/html; charset=utf-8",
+        "js" | "jsonp" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "txt" => "text/plain; charset=utf-8",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Headers that keep browsers from running anything but the viewer
+async fn security_headers(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    response
+}
+
+fn check_link(
+    hosting: &HostingConfig,
+    id: &str,
+    expires: i64,
+    signature: &str,
+) -> Option<Response> {
+    if !id.chars().all(|c| c.is_ascii_alphanumeric()) || !hosting.verify(id, expires, signature) {
+        return Some((StatusCode::FORBIDDEN, "Link is invalid or expired").into_response());
+    }
+    if !hosting.archive_path(id).exists() {
+        return Some((StatusCode::NOT_FOUND, "Archive not found").into_response());
+    }
+    None
+}
+
+/// Reads entry `index` of the archive in chunks, until it ends or the
+/// receiver is dropped
+fn stream_archive_entry(
+    mut archive: Archive,
+    index: usize,
+    chunks: mpsc::Sender<std::io::Result<Vec<u8>>>,
+) {
+    let mut entry = match archive.by_index(index) {
+        Ok(entry) => entry,
+        Err(err) => {
+            let _ = chunks.blocking_send(Err(err.into()));
+            return;
+        }
+    };
+    loop {
+        let mut chunk = vec![0; ENTRY_CHUNK_SIZE];
+        let chunk = match entry.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(read) => {
+                chunk.truncate(read);
+                Ok(chunk)
+            }
+            Err(err) => Err(err),
+        };
+        let failed = chunk.is_err();
+        if chunks.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn archive_index(Path((id, expires, signature)): Path<(String, i64, String)>) -> Redirect {
+    Redirect::permanent(&format!("/archive/{id}/{expires}/{signature}/archive.html"))
+}
+
+async fn archive_file(
+    State(state): State<AppState>,
+    Path((id, expires, signature, path)): Path<(String, i64, String, String)>,
+) -> Response {
+    if let Some(response) = check_link(&state.hosting, &id, expires, &signature) {
+        return response;
+    }
+    let data = state.clone();
+    let archive_id = id.clone();
+    let archive = match tokio::task::spawn_blocking(move || data.archive(&archive_id)).await {
+        Ok(Ok(archive)) => archive,
+        Ok(Err(err)) => {
+            println!("Failed to open archive {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Err(err) => {
+            println!("Failed to open archive {id}: {err:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let Some(index) = archive.index_for_name(&path) else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || stream_archive_entry(archive, index, sender));
+    let chunks = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    let mut response = (
+        [(header::CONTENT_TYPE, content_type(&path))],
+        Body::from_stream(chunks),
+    )
+        .into_response();
+    // Assets are uploaded by members, so they are never rendered as pages
+    if path.starts_with(archival::records::ASSETS_PREFIX) {
+        response.headers_mut().insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment"),
+        );
+    }
+    response
+}
+
+async fn download(
+    State(state): State<AppState>,
+    Path((id, expires, signature)): Path<(String, i64, String)>,
+) -> Response {
+    let hosting = &state.hosting;
+    if let Some(response) = check_link(hosting, &id, expires, &signature) {
+        return response;
+    }
+    let filename = tokio::fs::read(hosting.info_path(&id))
+        .await
+        .ok()
+        .and_then(|info| serde_json::from_slice::<HostedArchiveInfo>(&info).ok())
+        .map(|info| info.filename)
+        .unwrap_or_else(|| format!("{id}.zip"));
+    match tokio::fs::File::open(hosting.archive_path(&id)).await {
+        Ok(file) => (
+            [
+                (header::CONTENT_TYPE, "application/zip".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename*=UTF-8''{}",
+                        utils::web_files::sinks::encode_component(&filename)
+                    ),
+                ),
+            ],
+            Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+        )
+            .into_response(),
+        Err(err) => {
+            println!("Failed to read archive {id}: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Deletes hosted archives whose links have expired
+async fn remove_expired(state: &AppData) -> Result<()> {
+    let hosting = &state.hosting;
+    let now = poise::serenity_prelude::Timestamp::now();
+    let mut entries = match tokio::fs::read_dir(&hosting.storage_dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let info: HostedArchiveInfo = serde_json::from_slice(&tokio::fs::read(&path).await?)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if info.expires < now {
+            state.archives.lock().unwrap().remove(id);
+            let _ = tokio::fs::remove_file(hosting.archive_path(id)).await;
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn run(config: ServerConfig) -> Result<()> {
+    let state = Arc::new(AppData {
+        hosting: config.hosting,
+        archives: Default::default(),
+    });
+
+    let cleanup = state.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = remove_expired(&cleanup).await {
+                println!("Failed to remove expired archives: {err:?}");
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    });
+
+    let app = Router::new()
+        .route("/archive/{id}/{expires}/{signature}/", get(archive_index))
+        .route(
+            "/archive/{id}/{expires}/{signature}/{*path}",
+            get(archive_file),
+        )
+        .route("/download/{id}/{expires}/{signature}", get(download))
+        .layer(middleware::map_response(security_headers))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(config.bind)
+        .await
+        .with_context(|| format!("binding to {}", config.bind))?;
+    println!("Archive server listening on {}", config.bind);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
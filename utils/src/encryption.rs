@@ -0,0 +1,268 @@
+use crate::zip::ArchiveSettings;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{AesMode, ZipArchive, ZipWriter};
+
+/// Encryption of finished archives
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EncryptionConfig {
+    /// AES-256 encrypted zip entries, readable by 7-Zip and most archivers
+    Passphrase { passphrase: String },
+    /// The whole archive encrypted to `age1...` recipients, readable by
+    /// `age --decrypt` and `rage`
+    Age { recipients: Vec<String> },
+}
+
+// Keys shouldn't end up in logs
+impl std::fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionConfig::Passphrase { .. } => f.write_str("Passphrase"),
+            EncryptionConfig::Age { recipients } => f
+                .debug_struct("Age")
+                .field("recipients", recipients)
+                .finish(),
+        }
+    }
+}
+
+impl EncryptionConfig {
+    /// Passphrase the archive entries are encrypted with, if any
+    pub fn passphrase(&self) -> Option<&str> {
+        match self {
+            EncryptionConfig::Passphrase { passphrase } => Some(passphrase),
+            EncryptionConfig::Age { .. } => None,
+        }
+    }
+
+    /// Name of the archive once encrypted
+    pub fn encrypted_filename(&self, filename: &str) -> String {
+        match self {
+            EncryptionConfig::Passphrase { .. } => filename.to_string(),
+            EncryptionConfig::Age { .. } => format!("{filename}.age"),
+        }
+    }
+
+    /// Writes an encrypted copy of the archive at `path` into `out`
+    pub fn encrypt(
+        &self,
+        path: &Path,
+        settings: &ArchiveSettings,
+        out: &mut File,
+    ) -> anyhow::Result<()> {
+        match self {
+            EncryptionConfig::Passphrase { passphrase } => {
+                encrypt_zip(path, passphrase, settings.zip_file_options(), out)
+            }
+            EncryptionConfig::Age { recipients } => encrypt_age(path, recipients, out),
+        }
+    }
+}
+
+/// Secrets for reading encrypted archives
+#[derive(Clone, Default)]
+pub struct DecryptionKeys {
+    /// Passphrase of AES-encrypted zip entries
+    pub passphrase: Option<String>,
+    /// age identities (`AGE-SECRET-KEY-1...`) of age-encrypted archives
+    pub identities: Vec<String>,
+}
+
+impl std::fmt::Debug for DecryptionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptionKeys")
+            .field("passphrase", &self.passphrase.is_some())
+            .field("identities", &self.identities.len())
+            .finish()
+    }
+}
+
+/// Header every age file starts with
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// Whether the file at `path` is age-encrypted
+pub fn is_age_encrypted(path: &Path) -> anyhow::Result<bool> {
+    let mut header = vec![];
+    File::open(path)?
+        .take(AGE_MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+    Ok(header == AGE_MAGIC)
+}
+
+/// Writes the decrypted contents of the age file at `path` into `out`
+pub fn decrypt_age(path: &Path, identities: &[String], out: &mut File) -> anyhow::Result<()> {
+    if identities.is_empty() {
+        bail!("Archive is encrypted with age, an identity is needed to read it");
+    }
+    let identities = identities
+        .iter()
+        .map(|e| {
+            e.trim()
+                .parse::<age::x25519::Identity>()
+                .map_err(|err| anyhow::anyhow!("Bad age identity: {err}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let decryptor = age::Decryptor::new(std::io::BufReader::new(File::open(path)?))?;
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|e| e as &dyn age::Identity))
+        .context("decrypting archive")?;
+    std::io::copy(&mut reader, out)?;
+    Ok(())
+}
+
+fn encrypt_zip(
+    path: &Path,
+    passphrase: &str,
+    options: SimpleFileOptions,
+    out: &mut File,
+) -> anyhow::Result<()> {
+    let mut archive = ZipArchive::new(File::open(path)?)
+        .context("passphrase encryption only supports zip archives")?;
+    let mut zip = ZipWriter::new(out);
+    let options = options.with_aes_encryption(AesMode::Aes256, passphrase);
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        if entry.is_dir() {
+            zip.add_directory(name, options)?;
+        } else {
+            zip.start_file(name, options.large_file(entry.size() >= u32::MAX as u64))?;
+            std::io::copy(&mut entry, &mut zip)?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn encrypt_age(path: &Path, recipients: &[String], out: &mut File) -> anyhow::Result<()> {
+    if recipients.is_empty() {
+        bail!("No age recipients are configured");
+    }
+    let recipients = recipients
+        .iter()
+        .map(|e| {
+            e.parse::<age::x25519::Recipient>()
+                .map_err(|err| anyhow::anyhow!("Bad age recipient {e}: {err}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let encryptor =
+        age::Encryptor::with_recipients(recipients.iter().map(|e| e as &dyn age::Recipient))?;
+    let mut writer = encryptor.wrap_output(out)?;
+    std::io::copy(&mut File::open(path)?, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Seek;
+    use zip::result::ZipError;
+
+    const CONTENT: &str = "jsonp_parse([])";
+
+    /// Plain zip archive with a directory and a file
+    fn plain_archive() -> tempfile::NamedTempFile {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("assets")).unwrap();
+        std::fs::write(dir.path().join("messages.jsonp"), CONTENT).unwrap();
+        let mut archive = tempfile::NamedTempFile::new().unwrap();
+        crate::zip::write_directory(dir.path(), archive.as_file_mut(), &Default::default())
+            .unwrap();
+        archive
+    }
+
+    fn encrypted(config: &EncryptionConfig) -> tempfile::NamedTempFile {
+        let archive = plain_archive();
+        let mut out = tempfile::NamedTempFile::new().unwrap();
+        config
+            .encrypt(archive.path(), &Default::default(), out.as_file_mut())
+            .unwrap();
+        out
+    }
+
+    fn passphrase_config(passphrase: &str) -> EncryptionConfig {
+        EncryptionConfig::Passphrase {
+            passphrase: passphrase.to_string(),
+        }
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let file = encrypted(&passphrase_config("correct horse"));
+        assert!(!is_age_encrypted(file.path()).unwrap());
+        let mut archive = ZipArchive::new(File::open(file.path()).unwrap()).unwrap();
+        let index = archive.index_for_name("messages.jsonp").unwrap();
+        assert!(matches!(
+            archive.by_index(index),
+            Err(ZipError::UnsupportedArchive(_))
+        ));
+        let mut entry = archive.by_index_decrypt(index, b"correct horse").unwrap();
+        assert!(entry.encrypted());
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, CONTENT);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let file = encrypted(&passphrase_config("correct horse"));
+        let mut archive = ZipArchive::new(File::open(file.path()).unwrap()).unwrap();
+        let index = archive.index_for_name("messages.jsonp").unwrap();
+        assert!(matches!(
+            archive.by_index_decrypt(index, b"battery staple"),
+            Err(ZipError::InvalidPassword)
+        ));
+    }
+
+    #[test]
+    fn age_round_trip() {
+        let identity = age::x25519::Identity::generate();
+        let file = encrypted(&EncryptionConfig::Age {
+            recipients: vec![identity.to_public().to_string()],
+        });
+        assert!(is_age_encrypted(file.path()).unwrap());
+
+        let mut decrypted = tempfile::tempfile().unwrap();
+        let identities = vec![identity.to_string().expose_secret().to_string()];
+        decrypt_age(file.path(), &identities, &mut decrypted).unwrap();
+        decrypted.rewind().unwrap();
+        let mut archive = ZipArchive::new(decrypted).unwrap();
+        let mut content = String::new();
+        archive
+            .by_name("messages.jsonp")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, CONTENT);
+    }
+
+    #[test]
+    fn wrong_age_identity_is_rejected() {
+        let file = encrypted(&EncryptionConfig::Age {
+            recipients: vec![age::x25519::Identity::generate().to_public().to_string()],
+        });
+        let other = age::x25519::Identity::generate();
+        let mut decrypted = tempfile::tempfile().unwrap();
+        let identities = vec![other.to_string().expose_secret().to_string()];
+        assert!(decrypt_age(file.path(), &identities, &mut decrypted).is_err());
+        assert!(decrypt_age(file.path(), &[], &mut decrypted).is_err());
+    }
+
+    #[test]
+    fn detects_age_files() {
+        assert!(!is_age_encrypted(plain_archive().path()).unwrap());
+        let short = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(short.path(), "age").unwrap();
+        assert!(!is_age_encrypted(short.path()).unwrap());
+        let header = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(header.path(), "age-encryption.org/v1\n-> X25519").unwrap();
+        assert!(is_age_encrypted(header.path()).unwrap());
+    }
+}
@@ -1,7 +1,9 @@
 pub mod component_tools;
 pub mod confirmations;
+pub mod encryption;
 pub mod error_handle;
 pub mod into_edit;
+pub mod message_filter;
 pub mod messages_iter;
 pub mod reporter;
 pub mod web_files;
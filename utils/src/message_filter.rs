@@ -0,0 +1,131 @@
+use futures::{Stream, TryStreamExt};
+use lazy_regex::{regex, Regex};
+use poise::serenity_prelude::{Message, UserId};
+use std::fmt::{Display, Formatter};
+
+/// Criteria a message must meet to be archived or wiped. An empty filter
+/// matches every message
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    /// Only messages of these users, or of everyone when empty
+    pub authors: Vec<UserId>,
+    pub exclude_bots: bool,
+    pub bots_only: bool,
+    /// Case-insensitive text the content must contain
+    pub keyword: Option<String>,
+    /// Pattern the content must match
+    pub pattern: Option<Regex>,
+    pub with_attachments: bool,
+    pub pinned_only: bool,
+    pub exclude_pinned: bool,
+}
+
+impl MessageFilter {
+    /// Collects user IDs from mentions or plain IDs, separated by anything
+    pub fn parse_authors(text: &str) -> Vec<UserId> {
+        regex!(r"\d{15,20}")
+            .find_iter(text)
+            .filter_map(|e| e.as_str().parse::<u64>().ok())
+            .filter(|id| *id != 0)
+            .map(UserId::new)
+            .collect()
+    }
+
+    pub fn parse_pattern(pattern: &str) -> anyhow::Result<Regex> {
+        Regex::new(pattern).map_err(|err| anyhow::anyhow!("Invalid pattern `{pattern}`: {err}"))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.authors.is_empty()
+            && !self.exclude_bots
+            && !self.bots_only
+            && self.keyword.is_none()
+            && self.pattern.is_none()
+            && !self.with_attachments
+            && !self.pinned_only
+            && !self.exclude_pinned
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        if !self.authors.is_empty() && !self.authors.contains(&message.author.id) {
+            return false;
+        }
+        if self.exclude_bots && message.author.bot {
+            return false;
+        }
+        if self.bots_only && !message.author.bot {
+            return false;
+        }
+        if let Some(keyword) = &self.keyword {
+            if !message
+                .content
+                .to_lowercase()
+                .contains(&keyword.to_lowercase())
+            {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(&message.content) {
+                return false;
+            }
+        }
+        if self.with_attachments && message.attachments.is_empty() {
+            return false;
+        }
+        if self.pinned_only && !message.pinned {
+            return false;
+        }
+        if self.exclude_pinned && message.pinned {
+            return false;
+        }
+        true
+    }
+
+    /// Drops messages that don't match from the stream
+    pub fn apply<Messages: Stream<Item = anyhow::Result<Message>>>(
+        self,
+        messages: Messages,
+    ) -> impl Stream<Item = anyhow::Result<Message>> {
+        messages.try_filter(move |message| futures::future::ready(self.matches(message)))
+    }
+}
+
+impl Display for MessageFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("all messages");
+        }
+        let mut parts = vec![];
+        if !self.authors.is_empty() {
+            let authors = self
+                .authors
+                .iter()
+                .map(|e| format!("<@{e}>"))
+                .collect::<Vec<_>>();
+            parts.push(format!("from {}", authors.join(", ")));
+        }
+        if self.exclude_bots {
+            parts.push("not from bots".to_string());
+        }
+        if self.bots_only {
+            parts.push("from bots".to_string());
+        }
+        if let Some(keyword) = &self.keyword {
+            parts.push(format!("containing `{keyword}`"));
+        }
+        if let Some(pattern) = &self.pattern {
+            parts.push(format!("matching `{pattern}`"));
+        }
+        if self.with_attachments {
+            parts.push("with attachments".to_string());
+        }
+        if self.pinned_only {
+            parts.push("pinned".to_string());
+        }
+        if self.exclude_pinned {
+            parts.push("not pinned".to_string());
+        }
+        write!(f, "messages {}", parts.join(", "))
+    }
+}
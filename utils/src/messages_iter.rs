@@ -139,3 +139,137 @@ pub fn smart_messages_iter<H: AsRef<Http>>(
 ) -> impl Stream<Item = Result<Message>> {
     SmartMessagesIter::<H>::stream(http, channel_id, range)
 }
+
+fn holds_messages(kind: ChannelType) -> bool {
+    matches!(
+        kind,
+        ChannelType::Text
+            | ChannelType::News
+            | ChannelType::Voice
+            | ChannelType::Stage
+            | ChannelType::PublicThread
+            | ChannelType::PrivateThread
+            | ChannelType::NewsThread
+    )
+}
+
+/// Adds the archived threads of the channel to `threads`, keeping the pages
+/// listed before an error
+async fn archived_threads(
+    http: &Http,
+    channel_id: ChannelId,
+    private: bool,
+    threads: &mut Vec<GuildChannel>,
+) -> Result<()> {
+    let mut before = None;
+    loop {
+        let page = match private {
+            true => {
+                channel_id
+                    .get_archived_private_threads(http, before, Some(100))
+                    .await?
+            }
+            false => {
+                channel_id
+                    .get_archived_public_threads(http, before, Some(100))
+                    .await?
+            }
+        };
+        let oldest = page
+            .threads
+            .iter()
+            .filter_map(|e| e.thread_metadata.and_then(|e| e.archive_timestamp))
+            .min()
+            .map(|e| e.unix_timestamp() as u64);
+        let new = page
+            .threads
+            .into_iter()
+            .filter(|thread| !threads.iter().any(|e| e.id == thread.id))
+            .collect::<Vec<_>>();
+        // Guards against pagination that doesn't advance
+        if new.is_empty() {
+            break;
+        }
+        threads.extend(new);
+        if !page.has_more || oldest.is_none() {
+            break;
+        }
+        before = oldest;
+    }
+    Ok(())
+}
+
+/// Channels of a guild the bot can read
+#[derive(Debug, Clone, Default)]
+pub struct GuildMessageChannels {
+    /// Channels and threads ordered by position, threads following their
+    /// parent channel
+    pub channels: Vec<GuildChannel>,
+    /// Channels whose archived threads couldn't all be listed
+    pub incomplete: Vec<ChannelId>,
+}
+
+/// Every channel and thread of the guild that can hold messages and whose
+/// history the bot can read
+pub async fn guild_message_channels(
+    http: impl AsRef<Http>,
+    guild_id: GuildId,
+) -> anyhow::Result<GuildMessageChannels> {
+    let http = http.as_ref();
+    let guild = guild_id.to_partial_guild(http).await?;
+    let bot = guild_id
+        .member(http, http.get_current_user().await?.id)
+        .await?;
+    let mut channels = guild_id
+        .channels(http)
+        .await?
+        .into_values()
+        .collect::<Vec<_>>();
+    channels.sort_by_key(|e| (e.position, e.id));
+    let permissions = channels
+        .iter()
+        .map(|e| (e.id, guild.user_permissions_in(e, &bot)))
+        .collect::<std::collections::HashMap<_, _>>();
+    // Threads have the permissions of their parent channel
+    let readable = |id: Option<ChannelId>| {
+        id.and_then(|id| permissions.get(&id)).is_some_and(|e| {
+            e.contains(Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY)
+        })
+    };
+
+    let mut threads = guild_id.get_active_threads(http).await?.threads;
+    let mut incomplete = vec![];
+    for channel in &channels {
+        if !matches!(
+            channel.kind,
+            ChannelType::Text | ChannelType::News | ChannelType::Forum
+        ) || !readable(Some(channel.id))
+        {
+            continue;
+        }
+        let mut result = archived_threads(http, channel.id, false, &mut threads).await;
+        // Private threads are only listed for members who manage threads
+        if permissions[&channel.id].manage_threads() {
+            result = result.and(archived_threads(http, channel.id, true, &mut threads).await);
+        }
+        if result.is_err() {
+            incomplete.push(channel.id);
+        }
+    }
+    threads.sort_by_key(|e| e.id);
+    threads.dedup_by_key(|e| e.id);
+    threads.retain(|e| readable(e.parent_id));
+
+    let mut ordered = vec![];
+    for channel in channels {
+        let id = channel.id;
+        if holds_messages(channel.kind) && readable(Some(id)) {
+            ordered.push(channel);
+        }
+        ordered.extend(threads.iter().filter(|e| e.parent_id == Some(id)).cloned());
+    }
+    Ok(GuildMessageChannels {
+        channels: ordered,
+        incomplete,
+    })
+}
@@ -1,3 +1,5 @@
+use num_traits::ToPrimitive;
+use std::fmt::Display;
 use std::marker::PhantomData;
 use std::ops::Sub;
 use std::time::{Duration, Instant};
@@ -50,7 +52,10 @@ impl<Message, ReportFunc: Fn(Message, bool) -> Result, Result> Reporter<Message,
     }
 
     fn maybe_report(&mut self, message: Message, is_due: bool) -> Result {
-        self.last_report = Instant::now();
+        // Reports that aren't due don't restart the interval
+        if is_due {
+            self.last_report = Instant::now();
+        }
         (self.report_function)(message, is_due)
     }
 }
@@ -68,6 +73,10 @@ pub struct CountingReporter<
     pub current_count: Count,
     pub min_count: Count,
     pub max_count: Count,
+    /// Count expected at completion, for the percentage and time remaining
+    pub total: Option<Count>,
+    started: Instant,
+    start_count: Count,
     report_function: ReportFunc,
     message: PhantomData<Message>,
     result: PhantomData<Result>,
@@ -93,9 +102,12 @@ impl<
             min_interval,
             max_interval,
             last_count: current_count.clone(),
+            start_count: current_count.clone(),
             current_count,
             min_count,
             max_count,
+            total: None,
+            started: Instant::now(),
             report_function,
             message: Default::default(),
             result: Default::default(),
@@ -105,7 +117,72 @@ impl<
     pub fn into_report_function(self) -> ReportFunc {
         self.report_function
     }
+
+    pub fn with_total(mut self, total: Count) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Time since the reporter was created
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl<
+        Count: PartialOrd + Sub<Output = Count> + Clone + ToPrimitive + Display,
+        Message,
+        ReportFunc: Fn(Message, bool) -> Result,
+        Result,
+    > CountingReporter<Count, Message, ReportFunc, Result>
+{
+    /// Current count, with the percentage of the total, the throughput and
+    /// the estimated time remaining where they are known
+    pub fn progress(&self) -> String {
+        let current = self.current_count.to_f64().unwrap_or_default();
+        let mut text = self.current_count.to_string();
+        if let Some(total) = &self.total {
+            let percentage = match total.to_f64().unwrap_or_default() {
+                total if total > 0.0 => (current / total * 100.0).min(100.0),
+                _ => 100.0,
+            };
+            text.push_str(&format!("/{total} ({percentage:.0}%)"));
+        }
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let done = (self.current_count.clone() - self.start_count.clone())
+            .to_f64()
+            .unwrap_or_default();
+        if elapsed < 1.0 || done <= 0.0 {
+            return text;
+        }
+        let rate = done / elapsed;
+        match rate >= 10.0 {
+            true => text.push_str(&format!(", {rate:.0}/s")),
+            false => text.push_str(&format!(", {rate:.1}/s")),
+        }
+        if let Some(total) = self.total.as_ref().and_then(|e| e.to_f64()) {
+            let remaining = (total - current).max(0.0) / rate;
+            text.push_str(&format!(
+                ", about {} left",
+                format_duration(Duration::from_secs_f64(remaining))
+            ));
+        }
+        text
+    }
+}
+
+/// Formats a duration to its two largest units, such as `1h 5m` or `40s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{seconds}s"),
+        (0, _) => format!("{minutes}m {seconds}s"),
+        _ => format!("{hours}h {minutes}m"),
+    }
 }
+
 impl<
         Count: PartialOrd + Sub<Output = Count> + Clone + num_traits::Zero + num_traits::One,
         Message,
@@ -144,8 +221,10 @@ impl<
     }
 
     fn maybe_report(&mut self, message: Message, is_due: bool) -> Result {
-        self.last_report = Instant::now();
-        self.last_count = self.current_count.clone();
+        if is_due {
+            self.last_report = Instant::now();
+            self.last_count = self.current_count.clone();
+        }
         (self.report_function)(message, is_due)
     }
 }
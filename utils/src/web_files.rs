@@ -4,7 +4,9 @@ use serde::Deserialize;
 use std::path::Path;
 use tokio::io::AsyncWriteExt;
 
+pub mod hosting;
 pub mod messaged;
+pub mod sinks;
 
 #[must_use]
 #[derive(Debug, Deserialize)]
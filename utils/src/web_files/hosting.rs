@@ -0,0 +1,110 @@
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use poise::serenity_prelude::Timestamp;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn default_link_expiry_secs() -> u64 {
+    60 * 60 * 24 * 7
+}
+
+/// Settings of the archive hosting, shared by the bot and the built-in server
+#[derive(Debug, Clone, Deserialize)]
+pub struct HostingConfig {
+    /// Directory where hosted archives are kept
+    pub storage_dir: PathBuf,
+    /// URL under which the server is reachable, used to build links
+    pub public_url: String,
+    /// Key used to sign links
+    pub secret: String,
+    /// Validity of the links, hosted archives are deleted once it runs out
+    #[serde(default = "default_link_expiry_secs")]
+    pub link_expiry_secs: u64,
+}
+
+/// Information stored next to every hosted archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostedArchiveInfo {
+    pub filename: String,
+    pub expires: Timestamp,
+}
+
+#[must_use]
+#[derive(Debug, Clone)]
+pub struct HostedArchive {
+    /// Link to the archive viewer
+    pub link: String,
+    /// Link to the archive zip
+    pub download_link: String,
+    pub expires: Timestamp,
+}
+
+impl HostingConfig {
+    pub fn archive_path(&self, id: &str) -> PathBuf {
+        self.storage_dir.join(format!("{id}.zip"))
+    }
+
+    pub fn info_path(&self, id: &str) -> PathBuf {
+        self.storage_dir.join(format!("{id}.json"))
+    }
+
+    fn mac(&self, id: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(format!("{id}:{expires}").as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, id: &str, expires: i64) -> String {
+        hex::encode(self.mac(id, expires).finalize().into_bytes())
+    }
+
+    /// Checks that the signature matches and the link hasn't expired yet
+    pub fn verify(&self, id: &str, expires: i64, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        self.mac(id, expires).verify_slice(&signature).is_ok()
+            && expires > Timestamp::now().unix_timestamp()
+    }
+
+    /// Copies the archive into the storage and returns signed links to it
+    pub async fn store(
+        &self,
+        path: impl AsRef<Path>,
+        filename: &str,
+    ) -> anyhow::Result<HostedArchive> {
+        tokio::fs::create_dir_all(&self.storage_dir)
+            .await
+            .with_context(|| format!("creating {}", self.storage_dir.display()))?;
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let id = hex::encode(&Sha256::digest(format!("{nanos}:{filename}"))[..12]);
+
+        let expires = Timestamp::from_unix_timestamp(
+            Timestamp::now().unix_timestamp() + self.link_expiry_secs as i64,
+        )?;
+        tokio::fs::copy(path, self.archive_path(&id))
+            .await
+            .context("copying archive into the storage")?;
+        let info = HostedArchiveInfo {
+            filename: filename.to_string(),
+            expires,
+        };
+        tokio::fs::write(self.info_path(&id), serde_json::to_vec(&info)?).await?;
+
+        let expires_unix = expires.unix_timestamp();
+        let signature = self.sign(&id, expires_unix);
+        let base = self.public_url.trim_end_matches('/');
+        Ok(HostedArchive {
+            link: format!("{base}/archive/{id}/{expires_unix}/{signature}/archive.html"),
+            download_link: format!("{base}/download/{id}/{expires_unix}/{signature}"),
+            expires,
+        })
+    }
+}
@@ -1,8 +1,57 @@
+use crate::web_files::sinks::{store_with_fallback, SinkConfig};
 use anyhow::Context;
-use poise::serenity_prelude::{ChannelId, CreateAttachment, CreateMessage, Message};
+use poise::serenity_prelude::{ChannelId, CreateAttachment, CreateMessage, Message, PremiumTier};
 use std::fs::File;
 use std::path::Path;
 
+const MIB: u64 = 1024 * 1024;
+
+/// Room left for the rest of the request, to be safe
+const SIZE_MARGIN: u64 = MIB / 2;
+
+/// Attachment size limit of a guild with the given boost tier
+pub fn premium_tier_size_limit(tier: PremiumTier) -> u64 {
+    let limit = match tier {
+        PremiumTier::Tier2 => 50 * MIB,
+        PremiumTier::Tier3 => 100 * MIB,
+        _ => 10 * MIB,
+    };
+    limit - SIZE_MARGIN
+}
+
+/// Largest file that can be posted as an attachment in the current guild,
+/// unless overridden
+pub async fn effective_size_limit<T: Sync + Send>(
+    ctx: poise::Context<'_, T, anyhow::Error>,
+    size_limit_override: Option<u64>,
+) -> u64 {
+    if let Some(limit) = size_limit_override {
+        return limit;
+    }
+    let Some(guild_id) = ctx.guild_id() else {
+        return premium_tier_size_limit(PremiumTier::Tier0);
+    };
+    let cached_tier = ctx.guild().map(|guild| guild.premium_tier);
+    let tier = match cached_tier {
+        Some(tier) => tier,
+        None => guild_id
+            .to_partial_guild(ctx)
+            .await
+            .map(|guild| guild.premium_tier)
+            .unwrap_or(PremiumTier::Tier0),
+    };
+    premium_tier_size_limit(tier)
+}
+
+/// How files that don't fit into an attachment are handled
+#[derive(Debug, Clone, Copy)]
+pub struct UploadOptions<'a> {
+    /// Where to store files that are too large, tried in order
+    pub sinks: &'a [SinkConfig],
+    /// Attachment size limit, derived from the guild boost tier if absent
+    pub size_limit_override: Option<u64>,
+}
+
 pub async fn upload_file_and_message<T: Sync + Send>(
     ctx: poise::Context<'_, T, anyhow::Error>,
     channel: ChannelId,
@@ -10,11 +59,10 @@ pub async fn upload_file_and_message<T: Sync + Send>(
     path: impl AsRef<Path>,
     filename: String,
     message_prefix: String,
+    options: UploadOptions<'_>,
 ) -> anyhow::Result<Message> {
     let size = file.metadata()?.len();
-    // Less than 25 MB limit to be safe
-    const SIZE_LIMIT: u64 = 1000 * 1000 * 24;
-    let latest_message = if size < SIZE_LIMIT {
+    let latest_message = if size < effective_size_limit(ctx, options.size_limit_override).await {
         let tokio_file = tokio::fs::File::open(path).await?;
         channel
             .send_files(
@@ -24,20 +72,42 @@ pub async fn upload_file_and_message<T: Sync + Send>(
             )
             .await
             .context("uploading archive to discord")?
-    } else {
-        let uploaded = super::upload_file(path, filename)
+    } else if options.sinks.is_empty() {
+        channel
+            .send_message(
+                ctx.serenity_context(),
+                CreateMessage::new().content(format!(
+                    "{message_prefix}\nArchive is too large to be attached"
+                )),
+            )
             .await
-            .context("uploading archive to file.io")?;
+            .context("sending message to discord")?
+    } else {
+        let stored = store_with_fallback(options.sinks, path, &filename).await?;
 
-        let content = CreateMessage::new().content(format!(
-            "{message_prefix}\nDownload archive at {}\nFile will expire <t:{}:R>, or after {}",
-            uploaded.link,
-            uploaded.expires.unix_timestamp(),
-            pluralizer::pluralize("downloads", uploaded.max_downloads, true)
-        ));
+        let mut text = match &stored.link {
+            Some(link) => format!("{message_prefix}\nDownload archive at {link}"),
+            None => format!("{message_prefix}\nArchive stored at `{}`", stored.location),
+        };
+        match (stored.expires, stored.max_downloads) {
+            (Some(expires), Some(max_downloads)) => text.push_str(&format!(
+                "\nFile will expire <t:{}:R>, or after {}",
+                expires.unix_timestamp(),
+                pluralizer::pluralize("downloads", max_downloads, true)
+            )),
+            (Some(expires), None) => text.push_str(&format!(
+                "\nLink will expire <t:{}:R>",
+                expires.unix_timestamp()
+            )),
+            (None, Some(max_downloads)) => text.push_str(&format!(
+                "\nFile will expire after {}",
+                pluralizer::pluralize("downloads", max_downloads, true)
+            )),
+            (None, None) => {}
+        }
 
         channel
-            .send_message(ctx.serenity_context(), content)
+            .send_message(ctx.serenity_context(), CreateMessage::new().content(text))
             .await
             .context("sending link to discord")?
     };
@@ -0,0 +1,156 @@
+use anyhow::{bail, Context};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use poise::serenity_prelude::Timestamp;
+use serde::Deserialize;
+use std::future::Future;
+use std::path::Path;
+
+pub use http_put::HttpPutSink;
+pub use local::LocalSink;
+pub use s3::S3Sink;
+pub use sftp::SftpSink;
+
+mod http_put;
+mod local;
+mod s3;
+mod sftp;
+
+/// Characters that are left as-is in URL path segments and query values
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Percent-encodes everything except RFC 3986 unreserved characters
+pub fn encode_component(text: &str) -> String {
+    utf8_percent_encode(text, UNRESERVED).to_string()
+}
+
+/// Encodes every segment of a slash-separated path, keeping the separators
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(encode_component)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(crate) fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), encode_path(path))
+}
+
+/// Request body streaming the file at `path` instead of reading it to
+/// memory, with its length for the `Content-Length` header
+pub(crate) async fn file_body(path: &Path) -> anyhow::Result<(reqwest::Body, u64)> {
+    let file = tokio::fs::File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+    Ok((body, length))
+}
+
+/// Where an archive ended up after being stored by a sink
+#[must_use]
+#[derive(Debug, Clone)]
+pub struct StoredArchive {
+    /// Download link, if the sink provides one
+    pub link: Option<String>,
+    /// Human-readable location, shown when there is no link
+    pub location: String,
+    pub expires: Option<Timestamp>,
+    pub max_downloads: Option<isize>,
+}
+
+/// Destination for archives that are too large to be posted as attachments
+pub trait ArchiveSink {
+    /// Short name of the sink, used in error reports
+    fn name(&self) -> &'static str;
+
+    fn store(
+        &self,
+        path: &Path,
+        filename: &str,
+    ) -> impl Future<Output = anyhow::Result<StoredArchive>> + Send;
+}
+
+/// Anonymous upload to file.io
+#[derive(Debug, Clone, Default)]
+pub struct FileIoSink;
+
+impl ArchiveSink for FileIoSink {
+    fn name(&self) -> &'static str {
+        "file.io"
+    }
+
+    async fn store(&self, path: &Path, filename: &str) -> anyhow::Result<StoredArchive> {
+        let uploaded = super::upload_file(path, filename.to_string()).await?;
+        Ok(StoredArchive {
+            location: uploaded.link.clone(),
+            link: Some(uploaded.link),
+            expires: Some(uploaded.expires),
+            max_downloads: Some(uploaded.max_downloads),
+        })
+    }
+}
+
+/// Sink selection, as it appears in the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    FileIo,
+    Local(LocalSink),
+    S3(S3Sink),
+    Sftp(SftpSink),
+    HttpPut(HttpPutSink),
+}
+
+impl ArchiveSink for SinkConfig {
+    fn name(&self) -> &'static str {
+        match self {
+            SinkConfig::FileIo => FileIoSink.name(),
+            SinkConfig::Local(sink) => sink.name(),
+            SinkConfig::S3(sink) => sink.name(),
+            SinkConfig::Sftp(sink) => sink.name(),
+            SinkConfig::HttpPut(sink) => sink.name(),
+        }
+    }
+
+    async fn store(&self, path: &Path, filename: &str) -> anyhow::Result<StoredArchive> {
+        match self {
+            SinkConfig::FileIo => FileIoSink.store(path, filename).await,
+            SinkConfig::Local(sink) => sink.store(path, filename).await,
+            SinkConfig::S3(sink) => sink.store(path, filename).await,
+            SinkConfig::Sftp(sink) => sink.store(path, filename).await,
+            SinkConfig::HttpPut(sink) => sink.store(path, filename).await,
+        }
+    }
+}
+
+/// Default sink chain, matching the behavior before sinks were configurable
+pub fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::FileIo]
+}
+
+/// Stores the archive in the first sink that succeeds, trying them in order
+pub async fn store_with_fallback(
+    sinks: &[SinkConfig],
+    path: impl AsRef<Path>,
+    filename: &str,
+) -> anyhow::Result<StoredArchive> {
+    // Archive names are user-provided, don't let them escape the target directory
+    let filename = filename.replace(['/', '\\'], "_");
+    let mut errors = vec![];
+    for sink in sinks {
+        match sink
+            .store(path.as_ref(), &filename)
+            .await
+            .with_context(|| format!("storing archive in {}", sink.name()))
+        {
+            Ok(stored) => return Ok(stored),
+            Err(err) => errors.push(format!("{err:#}")),
+        }
+    }
+    if errors.is_empty() {
+        bail!("No archive sinks are configured");
+    }
+    bail!("All archive sinks failed:\n{}", errors.join("\n"))
+}
@@ -0,0 +1,57 @@
+use super::{encode_component, file_body, ArchiveSink, StoredArchive};
+use anyhow::bail;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Uploads archives with a plain HTTP PUT request
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpPutSink {
+    /// Upload URL, `{filename}` is replaced with the URL-encoded archive name
+    pub url: String,
+    /// Extra request headers, e.g. for authorization
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Download URL template, defaults to the upload URL
+    pub public_url: Option<String>,
+}
+
+impl ArchiveSink for HttpPutSink {
+    fn name(&self) -> &'static str {
+        "HTTP PUT"
+    }
+
+    async fn store(&self, path: &Path, filename: &str) -> anyhow::Result<StoredArchive> {
+        let encoded = encode_component(filename);
+        let url = self.url.replace("{filename}", &encoded);
+        let (body, length) = file_body(path).await?;
+
+        let mut request = reqwest::Client::new()
+            .put(&url)
+            .header(reqwest::header::CONTENT_LENGTH, length)
+            .body(body);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!(
+                "Server responded with {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        let link = self
+            .public_url
+            .as_ref()
+            .map(|url| url.replace("{filename}", &encoded))
+            .unwrap_or(url);
+        Ok(StoredArchive {
+            location: link.clone(),
+            link: Some(link),
+            expires: None,
+            max_downloads: None,
+        })
+    }
+}
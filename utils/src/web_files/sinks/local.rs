@@ -0,0 +1,34 @@
+use super::{join_url, ArchiveSink, StoredArchive};
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Copies archives into a directory on the local filesystem
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalSink {
+    pub dir: PathBuf,
+    /// URL under which `dir` is served, used to build download links
+    pub public_url: Option<String>,
+}
+
+impl ArchiveSink for LocalSink {
+    fn name(&self) -> &'static str {
+        "local directory"
+    }
+
+    async fn store(&self, path: &Path, filename: &str) -> anyhow::Result<StoredArchive> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let target = self.dir.join(filename);
+        tokio::fs::copy(path, &target)
+            .await
+            .with_context(|| format!("copying archive to {}", target.display()))?;
+        Ok(StoredArchive {
+            link: self.public_url.as_ref().map(|url| join_url(url, filename)),
+            location: target.display().to_string(),
+            expires: None,
+            max_downloads: None,
+        })
+    }
+}
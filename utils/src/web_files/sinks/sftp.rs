@@ -0,0 +1,104 @@
+use super::{join_url, ArchiveSink, StoredArchive};
+use anyhow::{bail, Context};
+use base64::Engine;
+use serde::Deserialize;
+use ssh2::{HashType, Session};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+fn default_port() -> u16 {
+    22
+}
+
+/// Uploads archives to a remote directory over SFTP
+#[derive(Debug, Clone, Deserialize)]
+pub struct SftpSink {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    /// Private key file, used instead of the password when set
+    pub private_key: Option<PathBuf>,
+    pub private_key_passphrase: Option<String>,
+    /// SHA256 fingerprint of the server host key, as printed by `ssh-keygen -lf`
+    pub host_key_fingerprint: String,
+    pub remote_dir: PathBuf,
+    /// URL under which `remote_dir` is served, used to build download links
+    pub public_url: Option<String>,
+}
+
+impl SftpSink {
+    fn verify_host_key(&self, session: &Session) -> anyhow::Result<()> {
+        let hash = session
+            .host_key_hash(HashType::Sha256)
+            .context("server did not provide a host key")?;
+        let fingerprint = base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash);
+        let expected = self
+            .host_key_fingerprint
+            .trim()
+            .trim_start_matches("SHA256:")
+            .trim_end_matches('=');
+        if fingerprint != expected {
+            bail!("Host key fingerprint mismatch, got SHA256:{fingerprint}");
+        }
+        Ok(())
+    }
+
+    fn upload_blocking(&self, path: &Path, filename: &str) -> anyhow::Result<PathBuf> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("connecting to {}:{}", self.host, self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake().context("performing SSH handshake")?;
+        self.verify_host_key(&session)?;
+
+        if let Some(key) = &self.private_key {
+            session
+                .userauth_pubkey_file(
+                    &self.username,
+                    None,
+                    key,
+                    self.private_key_passphrase.as_deref(),
+                )
+                .context("authenticating with private key")?;
+        } else if let Some(password) = &self.password {
+            session
+                .userauth_password(&self.username, password)
+                .context("authenticating with password")?;
+        } else {
+            bail!("Neither password nor private key is configured");
+        }
+
+        let sftp = session.sftp()?;
+        let remote_path = self.remote_dir.join(filename);
+        let mut remote = sftp
+            .create(&remote_path)
+            .with_context(|| format!("creating {}", remote_path.display()))?;
+        let mut local = std::fs::File::open(path)?;
+        std::io::copy(&mut local, &mut remote).context("transferring archive")?;
+        Ok(remote_path)
+    }
+}
+
+impl ArchiveSink for SftpSink {
+    fn name(&self) -> &'static str {
+        "SFTP"
+    }
+
+    async fn store(&self, path: &Path, filename: &str) -> anyhow::Result<StoredArchive> {
+        let sink = self.clone();
+        let path = path.to_path_buf();
+        let owned_filename = filename.to_string();
+        // libssh2 is blocking
+        let remote_path =
+            tokio::task::spawn_blocking(move || sink.upload_blocking(&path, &owned_filename))
+                .await??;
+        Ok(StoredArchive {
+            link: self.public_url.as_ref().map(|url| join_url(url, filename)),
+            location: format!("{}:{}", self.host, remote_path.display()),
+            expires: None,
+            max_downloads: None,
+        })
+    }
+}
@@ -1,13 +1,150 @@
 use anyhow::Context;
-use std::io::{Read, Write};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Seek, Write};
 use std::path::Path;
 use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
 
-pub fn archive_directory(dir: &Path, out_file: &mut std::fs::File) -> anyhow::Result<()> {
-    let options = zip::write::FileOptions::<()>::default().unix_permissions(0o755);
-    let mut zip = zip::ZipWriter::new(out_file);
-    let mut buffer = Vec::new();
-    for entry in WalkDir::new(dir).into_iter() {
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// Container of the archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    TarZst,
+}
+
+/// Compression method of zip entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZipCompression {
+    Stored,
+    #[default]
+    Deflated,
+    Bzip2,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ArchiveSettings {
+    pub format: ArchiveFormat,
+    /// Ignored by `tar.zst`, which is always zstd-compressed
+    pub compression: ZipCompression,
+    /// Compression level, the default of the method when absent
+    pub level: Option<i64>,
+}
+
+impl ArchiveSettings {
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    /// Options for zip entries, with fixed timestamps and permissions
+    pub fn zip_file_options(&self) -> SimpleFileOptions {
+        let method = match self.compression {
+            ZipCompression::Stored => CompressionMethod::Stored,
+            ZipCompression::Deflated => CompressionMethod::Deflated,
+            ZipCompression::Bzip2 => CompressionMethod::Bzip2,
+            ZipCompression::Zstd => CompressionMethod::Zstd,
+        };
+        SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(self.level)
+            .last_modified_time(DateTime::default())
+            .unix_permissions(FILE_MODE)
+    }
+}
+
+/// Archive writer that streams files into the output instead of reading
+/// them into memory
+pub enum ArchiveWriter<W: Write + Seek> {
+    Zip {
+        zip: Box<ZipWriter<W>>,
+        options: SimpleFileOptions,
+    },
+    TarZst(tar::Builder<zstd::Encoder<'static, W>>),
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(out: W, settings: &ArchiveSettings) -> anyhow::Result<Self> {
+        Ok(match settings.format {
+            ArchiveFormat::Zip => ArchiveWriter::Zip {
+                zip: Box::new(ZipWriter::new(out)),
+                options: settings.zip_file_options(),
+            },
+            ArchiveFormat::TarZst => {
+                let level = settings.level.unwrap_or(0) as i32;
+                let encoder = zstd::Encoder::new(out, level)?;
+                let mut tar = tar::Builder::new(encoder);
+                tar.mode(tar::HeaderMode::Deterministic);
+                ArchiveWriter::TarZst(tar)
+            }
+        })
+    }
+
+    pub fn add_directory(&mut self, name: &str) -> anyhow::Result<()> {
+        match self {
+            ArchiveWriter::Zip { zip, options } => {
+                zip.add_directory(name, options.unix_permissions(DIR_MODE))?
+            }
+            ArchiveWriter::TarZst(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(DIR_MODE);
+                header.set_mtime(0);
+                header.set_size(0);
+                tar.append_data(&mut header, name, std::io::empty())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the file at `path` into the archive under `name`
+    pub fn add_file(&mut self, name: &str, path: &Path) -> anyhow::Result<()> {
+        let mut file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let size = file.metadata()?.len();
+        match self {
+            ArchiveWriter::Zip { zip, options } => {
+                zip.start_file(name, options.large_file(size >= u32::MAX as u64))?;
+                std::io::copy(&mut file, zip)?;
+            }
+            ArchiveWriter::TarZst(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_mode(FILE_MODE);
+                header.set_mtime(0);
+                header.set_size(size);
+                tar.append_data(&mut header, name, file)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<W> {
+        Ok(match self {
+            ArchiveWriter::Zip { zip, .. } => (*zip).finish()?,
+            ArchiveWriter::TarZst(tar) => tar.into_inner()?.finish()?,
+        })
+    }
+}
+
+/// Archives the contents of `dir` in a deterministic order, so identical
+/// directories produce identical archives
+pub fn write_directory(
+    dir: &Path,
+    out: &mut File,
+    settings: &ArchiveSettings,
+) -> anyhow::Result<()> {
+    let mut writer = ArchiveWriter::new(out, settings)?;
+    for entry in WalkDir::new(dir).sort_by_file_name() {
         let entry = entry.context("Serializing files")?;
         let path = entry.path();
         let name = path
@@ -15,15 +152,64 @@ pub fn archive_directory(dir: &Path, out_file: &mut std::fs::File) -> anyhow::Re
             .to_str()
             .ok_or_else(|| anyhow::anyhow!("Bad file name"))?;
 
-        if path.is_file() {
-            zip.start_file(name, options)?;
-            let mut f = std::fs::File::open(path)?;
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-            buffer.clear();
+        if entry.file_type().is_file() {
+            writer.add_file(name, path)?;
         } else if !name.is_empty() {
-            zip.add_directory(name, options)?;
+            writer.add_directory(name)?;
         }
     }
+    writer.finish()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn archive_bytes(dir: &Path, settings: &ArchiveSettings) -> Vec<u8> {
+        let mut out = tempfile::tempfile().unwrap();
+        write_directory(dir, &mut out, settings).unwrap();
+        let mut bytes = vec![];
+        out.rewind().unwrap();
+        out.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Same files, created in a different order and at different times
+    fn directories() -> (tempfile::TempDir, tempfile::TempDir) {
+        let files = [
+            ("messages.jsonp", "jsonp_parse([])"),
+            ("assets/1.png", "image"),
+            ("assets/thumbnails/1.webp", "thumbnail"),
+            ("archive.html", "<html></html>"),
+        ];
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        for (dir, files) in [
+            (&first, files.to_vec()),
+            (&second, files.into_iter().rev().collect()),
+        ] {
+            for (name, content) in files {
+                let path = dir.path().join(name);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            }
+        }
+        (first, second)
+    }
+
+    #[test]
+    fn write_directory_is_deterministic() {
+        let (first, second) = directories();
+        for format in [ArchiveFormat::Zip, ArchiveFormat::TarZst] {
+            let settings = ArchiveSettings {
+                format,
+                ..Default::default()
+            };
+            let bytes = archive_bytes(first.path(), &settings);
+            assert_eq!(bytes, archive_bytes(first.path(), &settings), "{format:?}");
+            assert_eq!(bytes, archive_bytes(second.path(), &settings), "{format:?}");
+        }
+    }
+}
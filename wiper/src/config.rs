@@ -0,0 +1,32 @@
+//! Wiper settings, as they appear in the bot configuration
+
+use crate::wiping::PreservationRules;
+use poise::serenity_prelude::GuildId;
+use serde::Deserialize;
+
+/// Discord's message deletion route reports a bucket of 5 requests in its
+/// ratelimit headers, requests beyond it only wait in serenity's ratelimiter
+const DEFAULT_DELETE_CONCURRENCY: usize = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WiperConfig {
+    /// Single deletions in flight at once
+    pub delete_concurrency: usize,
+}
+
+impl Default for WiperConfig {
+    fn default() -> Self {
+        WiperConfig {
+            delete_concurrency: DEFAULT_DELETE_CONCURRENCY,
+        }
+    }
+}
+
+/// Bot data that provides the wiper configuration
+pub trait WiperData: Send + Sync {
+    fn wiper_config(&self) -> &WiperConfig;
+
+    /// Messages of the guild that wipes and purges never delete
+    fn preservation_rules(&self, guild_id: Option<GuildId>) -> PreservationRules;
+}
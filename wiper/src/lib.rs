@@ -1 +1,4 @@
+pub mod config;
+pub mod planner;
+pub mod purge;
 pub mod wiping;
@@ -0,0 +1,242 @@
+//! Splitting of messages into bulk and single deletions
+
+use anyhow::Result;
+use chrono::Days;
+use futures::{Stream, StreamExt};
+use poise::serenity_prelude::{ChannelId, Message, MessageId, Timestamp};
+use std::fmt::{Display, Formatter};
+
+/// Discord only bulk deletes messages younger than two weeks, a day is left
+/// as a margin for the time the deletion takes
+const BULK_MAX_AGE_DAYS: u64 = 13;
+const BULK_MIN: usize = 2;
+const BULK_MAX: usize = 100;
+
+/// Messages of one channel deleted by a single bulk request
+#[derive(Debug, Clone)]
+pub struct BulkDeletion {
+    pub channel: ChannelId,
+    pub messages: Vec<MessageId>,
+}
+
+/// How a set of messages is deleted: in bulk where Discord allows it, one
+/// request per message otherwise
+#[derive(Debug, Clone, Default)]
+pub struct DeletionPlan {
+    pub bulk: Vec<BulkDeletion>,
+    pub single: Vec<(ChannelId, MessageId)>,
+}
+
+impl DeletionPlan {
+    /// Plans the deletion of `messages`, in any order and from any channels
+    pub fn new(messages: impl IntoIterator<Item = (ChannelId, MessageId, Timestamp)>) -> Self {
+        let bulk_after = Timestamp::from(
+            Timestamp::now()
+                .checked_sub_days(Days::new(BULK_MAX_AGE_DAYS))
+                .expect("Invalid system clock time"),
+        );
+
+        let mut recent: Vec<(ChannelId, MessageId)> = vec![];
+        let mut plan = DeletionPlan::default();
+        for (channel, message, timestamp) in messages {
+            match timestamp > bulk_after {
+                true => recent.push((channel, message)),
+                false => plan.single.push((channel, message)),
+            }
+        }
+
+        recent.sort_by_key(|(channel, _)| *channel);
+        for group in recent.chunk_by(|a, b| a.0 == b.0) {
+            let channel = group[0].0;
+            // Spread the messages evenly, so no request is left with a
+            // single message that can't be bulk deleted
+            let requests = group.len().div_ceil(BULK_MAX);
+            let size = group.len().div_ceil(requests.max(1));
+            for chunk in group.chunks(size.max(1)) {
+                match chunk.len() >= BULK_MIN {
+                    true => plan.bulk.push(BulkDeletion {
+                        channel,
+                        messages: chunk.iter().map(|(_, id)| *id).collect(),
+                    }),
+                    false => plan.single.extend_from_slice(chunk),
+                }
+            }
+        }
+        plan
+    }
+
+    /// Plans the deletion of every message of the stream
+    pub async fn collect<Messages: Stream<Item = Result<Message>>>(
+        messages: Messages,
+    ) -> Result<Self> {
+        let mut messages = std::pin::pin!(messages);
+        let mut planned = vec![];
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            planned.push((message.channel_id, message.id, message.timestamp));
+        }
+        Ok(Self::new(planned))
+    }
+
+    /// Number of messages the plan deletes
+    pub fn len(&self) -> usize {
+        self.bulk.iter().map(|e| e.messages.len()).sum::<usize>() + self.single.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bulk.is_empty() && self.single.is_empty()
+    }
+
+    /// Number of requests the plan takes
+    pub fn requests(&self) -> usize {
+        self.bulk.len() + self.single.len()
+    }
+}
+
+impl Display for DeletionPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut channels = self
+            .bulk
+            .iter()
+            .map(|e| e.channel)
+            .chain(self.single.iter().map(|(channel, _)| *channel))
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels.dedup();
+
+        writeln!(
+            f,
+            "{} in {}",
+            pluralizer::pluralize("messages", self.len() as isize, true),
+            pluralizer::pluralize("requests", self.requests() as isize, true),
+        )?;
+        for channel in channels {
+            let bulk = self
+                .bulk
+                .iter()
+                .filter(|e| e.channel == channel)
+                .map(|e| e.messages.len().to_string())
+                .collect::<Vec<_>>();
+            let single = self.single.iter().filter(|e| e.0 == channel).count();
+            let mut parts = vec![];
+            if !bulk.is_empty() {
+                parts.push(format!(
+                    "{} bulk {} ({} messages)",
+                    bulk.len(),
+                    pluralizer::pluralize("requests", bulk.len() as isize, false),
+                    bulk.join(" + ")
+                ));
+            }
+            if single > 0 {
+                parts.push(format!(
+                    "{single} single {}",
+                    pluralizer::pluralize("deletions", single as isize, false)
+                ));
+            }
+            writeln!(f, "<#{channel}>: {}", parts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days_ago(days: i64) -> Timestamp {
+        Timestamp::from_unix_timestamp(Timestamp::now().unix_timestamp() - days * 24 * 60 * 60)
+            .unwrap()
+    }
+
+    fn messages(
+        channel: u64,
+        count: u64,
+        timestamp: Timestamp,
+    ) -> Vec<(ChannelId, MessageId, Timestamp)> {
+        (1..=count)
+            .map(|id| (ChannelId::new(channel), MessageId::new(id), timestamp))
+            .collect()
+    }
+
+    fn bulk_sizes(plan: &DeletionPlan) -> Vec<usize> {
+        plan.bulk.iter().map(|e| e.messages.len()).collect()
+    }
+
+    #[test]
+    fn single_recent_message() {
+        let plan = DeletionPlan::new(messages(1, 1, days_ago(0)));
+        assert!(plan.bulk.is_empty());
+        assert_eq!(plan.single.len(), 1);
+        assert_eq!(plan.requests(), 1);
+    }
+
+    #[test]
+    fn two_recent_messages() {
+        let plan = DeletionPlan::new(messages(1, 2, days_ago(0)));
+        assert_eq!(bulk_sizes(&plan), vec![2]);
+        assert!(plan.single.is_empty());
+    }
+
+    #[test]
+    fn hundred_recent_messages() {
+        let plan = DeletionPlan::new(messages(1, 100, days_ago(0)));
+        assert_eq!(bulk_sizes(&plan), vec![100]);
+        assert_eq!(plan.requests(), 1);
+    }
+
+    #[test]
+    fn hundred_and_one_recent_messages() {
+        // Split evenly rather than leaving a lone message for a single deletion
+        let plan = DeletionPlan::new(messages(1, 101, days_ago(0)));
+        assert_eq!(bulk_sizes(&plan), vec![51, 50]);
+        assert!(plan.single.is_empty());
+        assert_eq!(plan.len(), 101);
+    }
+
+    #[test]
+    fn bulk_age_cutoff() {
+        let mut all = messages(1, 3, days_ago(BULK_MAX_AGE_DAYS as i64 - 1));
+        all.extend(
+            messages(1, 3, days_ago(BULK_MAX_AGE_DAYS as i64 + 1))
+                .into_iter()
+                .map(|(channel, id, time)| (channel, MessageId::new(id.get() + 10), time)),
+        );
+        let plan = DeletionPlan::new(all);
+        assert_eq!(bulk_sizes(&plan), vec![3]);
+        assert_eq!(plan.single.len(), 3);
+        assert!(plan.single.iter().all(|(_, id)| id.get() > 10));
+    }
+
+    #[test]
+    fn mixed_ages_and_channels() {
+        let mut all = messages(2, 150, days_ago(1));
+        all.extend(messages(1, 1, days_ago(1)));
+        all.extend(messages(3, 4, days_ago(30)));
+        all.extend(
+            messages(1, 2, days_ago(2))
+                .into_iter()
+                .map(|(channel, id, time)| (channel, MessageId::new(id.get() + 1), time)),
+        );
+        let plan = DeletionPlan::new(all);
+
+        assert!(plan.bulk.iter().all(|e| e.messages.len() <= BULK_MAX));
+        // Channels never share a bulk request
+        let channel_sizes = plan
+            .bulk
+            .iter()
+            .map(|e| (e.channel.get(), e.messages.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(channel_sizes, vec![(1, 3), (2, 75), (2, 75)]);
+        assert_eq!(plan.single.len(), 4);
+        assert!(plan.single.iter().all(|(channel, _)| channel.get() == 3));
+        assert_eq!(plan.len(), 157);
+        assert_eq!(plan.requests(), 7);
+    }
+
+    #[test]
+    fn empty() {
+        let plan = DeletionPlan::new(vec![]);
+        assert!(plan.is_empty());
+        assert_eq!(plan.requests(), 0);
+    }
+}
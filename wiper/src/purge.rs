@@ -0,0 +1,350 @@
+//! Deletion of a range of messages in the current channel, independent of
+//! archival
+
+use crate::config::WiperData;
+use crate::planner::DeletionPlan;
+use crate::wiping::{execute_plan, WipeSummary};
+use anyhow::{Context as AnyhowContext, Result};
+use chrono::Duration as ChronoDuration;
+use futures::{Stream, StreamExt, TryStreamExt};
+use poise::serenity_prelude::{ButtonStyle, Message, MessageId, Timestamp};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use utils::command_handler_wrapper;
+use utils::component_tools::clear_components;
+use utils::confirmations::{confirm_buttons, BtnConfirmOptions};
+use utils::into_edit::IntoEdit;
+use utils::message_filter::MessageFilter;
+use utils::messages_iter::{smart_messages_iter, MessagesRange};
+
+type Context<'a, T> = poise::Context<'a, T, anyhow::Error>;
+
+#[macro_export]
+macro_rules! purge_command {
+    ($name:ident, $data:ty) => {
+        /// Delete messages of the current channel
+        #[poise::command(
+            slash_command,
+            prefix_command,
+            required_permissions = "MANAGE_MESSAGES",
+            default_member_permissions = "MANAGE_MESSAGES",
+            required_bot_permissions = "MANAGE_MESSAGES|READ_MESSAGE_HISTORY",
+            guild_only
+        )]
+        #[allow(clippy::too_many_arguments)]
+        async fn $name(
+            ctx: poise::Context<'_, $data, anyhow::Error>,
+            #[description = "Only messages after this message, as ID or link"] after: Option<
+                String,
+            >,
+            #[description = "Only messages before this message, as ID or link"] before: Option<
+                String,
+            >,
+            #[description = "Only the most recent N matching messages"] last: Option<usize>,
+            #[description = "Only messages sent since, as a duration like 2h or a timestamp"]
+                                                                            since: Option<String>,
+            #[description = "Only messages of these users, as mentions or IDs"] authors: Option<
+                String,
+            >,
+            #[description = "Only messages of bots"] bots_only: Option<bool>,
+            #[description = "Only messages containing this text"] contains: Option<String>,
+            #[description = "Only messages with attachments"] with_attachments: Option<bool>,
+            #[description = "Keep pinned messages"] exclude_pinned: Option<bool>,
+            #[description = "Only show how the messages would be deleted"] dry_run: Option<bool>,
+        ) -> Result<()> {
+            let range = wiper::purge::PurgeRange {
+                after: after
+                    .as_deref()
+                    .map(wiper::purge::parse_message_id)
+                    .transpose()?,
+                before: before
+                    .as_deref()
+                    .map(wiper::purge::parse_message_id)
+                    .transpose()?,
+                last,
+                since: since
+                    .as_deref()
+                    .map(wiper::purge::parse_since)
+                    .transpose()?,
+            };
+            let filter = utils::message_filter::MessageFilter {
+                authors: authors
+                    .as_deref()
+                    .map(utils::message_filter::MessageFilter::parse_authors)
+                    .unwrap_or_default(),
+                bots_only: bots_only.unwrap_or(false),
+                keyword: contains,
+                with_attachments: with_attachments.unwrap_or(false),
+                exclude_pinned: exclude_pinned.unwrap_or(false),
+                ..Default::default()
+            };
+            wiper::purge::purge(ctx, range, filter, dry_run.unwrap_or(false)).await
+        }
+    };
+}
+
+/// Bounds of the messages a purge deletes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PurgeRange {
+    pub after: Option<MessageId>,
+    pub before: Option<MessageId>,
+    /// Stop after this many matching messages, newest first
+    pub last: Option<usize>,
+    pub since: Option<Timestamp>,
+}
+
+impl Display for PurgeRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(last) = self.last {
+            write!(f, ", the last {last} of them")?;
+        }
+        if let Some(after) = self.after {
+            write!(f, ", after {after}")?;
+        }
+        if let Some(before) = self.before {
+            write!(f, ", before {before}")?;
+        }
+        if let Some(since) = self.since {
+            write!(f, ", sent since <t:{}:f>", since.unix_timestamp())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a message ID, or takes it from a message link
+pub fn parse_message_id(text: &str) -> Result<MessageId> {
+    text.trim()
+        .rsplit('/')
+        .next()
+        .and_then(|id| id.parse::<u64>().ok())
+        .filter(|id| *id != 0)
+        .map(MessageId::new)
+        .ok_or_else(|| anyhow::anyhow!("`{text}` is not a message ID or link"))
+}
+
+/// Parses a duration back from now such as `90m`, `2h` or `3d`, a Discord
+/// timestamp such as `<t:1700000000>` or an RFC 3339 time
+pub fn parse_since(text: &str) -> Result<Timestamp> {
+    let text = text.trim();
+    let invalid = || anyhow::anyhow!("`{text}` is not a duration or a time");
+    if let Some(unix) = text.strip_prefix("<t:") {
+        let unix = unix
+            .trim_end_matches('>')
+            .split(':')
+            .next()
+            .and_then(|e| e.parse::<i64>().ok())
+            .ok_or_else(invalid)?;
+        return Timestamp::from_unix_timestamp(unix).map_err(|_| invalid());
+    }
+    if let Ok(time) = Timestamp::parse(text) {
+        return Ok(time);
+    }
+    let unit = text.chars().last().ok_or_else(invalid)?;
+    let amount = text[..text.len() - unit.len_utf8()]
+        .parse::<i64>()
+        .map_err(|_| invalid())?;
+    let duration = match unit {
+        's' => ChronoDuration::try_seconds(amount),
+        'm' => ChronoDuration::try_minutes(amount),
+        'h' => ChronoDuration::try_hours(amount),
+        'd' => ChronoDuration::try_days(amount),
+        'w' => ChronoDuration::try_weeks(amount),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+    Timestamp::now()
+        .checked_sub_signed(duration)
+        .map(Timestamp::from)
+        .ok_or_else(invalid)
+}
+
+/// Messages of the current channel in `range` that match the filter,
+/// newest first
+fn purged_messages<'a, T: Send + Sync>(
+    ctx: Context<'a, T>,
+    range: PurgeRange,
+    filter: MessageFilter,
+) -> impl Stream<Item = Result<Message>> + Send + 'a {
+    let messages = smart_messages_iter(
+        ctx,
+        ctx.channel_id(),
+        MessagesRange {
+            before: range.before,
+            after: None,
+        },
+    )
+    .map_err(anyhow::Error::from)
+    // The stream goes back in time, so it ends at the first message out of range
+    .try_take_while(move |message: &Message| {
+        let in_range = range.after.is_none_or(|after| message.id > after)
+            && range.since.is_none_or(|since| message.timestamp >= since);
+        futures::future::ready(Ok(in_range))
+    });
+    filter
+        .apply(messages)
+        .take(range.last.unwrap_or(usize::MAX))
+}
+
+pub async fn purge<T: WiperData>(
+    ctx: Context<'_, T>,
+    range: PurgeRange,
+    filter: MessageFilter,
+    dry_run: bool,
+) -> Result<()> {
+    command_handler_wrapper!(handle_purge(ctx, range, filter, dry_run))
+}
+
+async fn handle_purge<T: WiperData>(
+    ctx: Context<'_, T>,
+    mut range: PurgeRange,
+    filter: MessageFilter,
+    dry_run: bool,
+) -> Result<()> {
+    let description = format!("{filter}{range}");
+    let mut reply = ctx.say("Counting messages").await?.into_message().await?;
+    // Never delete the command or the reply
+    if range.before.is_none() {
+        range.before = Some(match ctx {
+            poise::Context::Prefix(ctx) => ctx.msg.id,
+            _ => reply.id,
+        });
+    }
+
+    let preserve = ctx.data().preservation_rules(ctx.guild_id());
+    let mut summary = WipeSummary::default();
+    let messages = purged_messages(ctx, range, filter.clone()).try_filter(|message| {
+        let preserved = preserve.preserves(message);
+        if preserved {
+            summary.preserved.push(message.link());
+        }
+        futures::future::ready(!preserved)
+    });
+    let plan = DeletionPlan::collect(messages)
+        .await
+        .context("counting messages")?;
+    let count = plan.len();
+    let kept = match summary.preserved.len() {
+        0 => String::new(),
+        preserved => format!(
+            ", {} kept by the preservation rules",
+            pluralizer::pluralize("messages", preserved as isize, true)
+        ),
+    };
+    if count == 0 {
+        reply
+            .edit(ctx, format!("No {description} to delete{kept}").into_edit())
+            .await?;
+        return Ok(());
+    }
+
+    let found = format!(
+        "Found {} ({description}){kept}",
+        pluralizer::pluralize("messages", count as isize, true)
+    );
+    if dry_run {
+        reply
+            .edit(ctx, format!("{found}\n{plan}").into_edit())
+            .await?;
+        return Ok(());
+    }
+    reply
+        .edit(ctx, format!("{found}. Delete them?").into_edit())
+        .await?;
+    let confirmed = confirm_buttons(
+        ctx,
+        &mut reply,
+        BtnConfirmOptions {
+            confirm_text: format!(
+                "Delete {}",
+                pluralizer::pluralize("messages", count as isize, true)
+            ),
+            confirm_style: ButtonStyle::Danger,
+            cancel_text: "Cancel".to_string(),
+            cancel_style: ButtonStyle::Primary,
+            timeout: Duration::from_secs(15),
+        },
+    )
+    .await?
+    .bool();
+    clear_components(ctx, &mut reply).await?;
+    if !confirmed {
+        reply.edit(ctx, "Purge canceled".into_edit()).await?;
+        return Ok(());
+    }
+
+    let channel = reply.channel_id;
+    let status = reply.id;
+    execute_plan(
+        ctx,
+        plan,
+        summary,
+        || purged_messages(ctx, range, filter.clone()),
+        |text, is_due| async move {
+            if is_due {
+                channel.edit_message(ctx, status, text.into_edit()).await?;
+            }
+            Ok(())
+        },
+    )
+    .await
+    .context("deleting messages")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_id_from_link_or_id() {
+        let id = MessageId::new(1234567890123456789);
+        assert_eq!(parse_message_id("1234567890123456789").unwrap(), id);
+        assert_eq!(parse_message_id(" 1234567890123456789 ").unwrap(), id);
+        assert_eq!(
+            parse_message_id(
+                "https://discord.com/channels/111111111111111111/222222222222222222/1234567890123456789"
+            )
+            .unwrap(),
+            id
+        );
+        assert!(parse_message_id("0").is_err());
+        assert!(parse_message_id("not a message").is_err());
+        assert!(parse_message_id("https://discord.com/channels/1/2/").is_err());
+    }
+
+    #[test]
+    fn since_discord_timestamp() {
+        let expected = Timestamp::from_unix_timestamp(1700000000).unwrap();
+        assert_eq!(parse_since("<t:1700000000>").unwrap(), expected);
+        assert_eq!(parse_since("<t:1700000000:R>").unwrap(), expected);
+        assert!(parse_since("<t:soon>").is_err());
+    }
+
+    #[test]
+    fn since_rfc_3339() {
+        assert_eq!(
+            parse_since("2023-11-14T22:13:20Z").unwrap(),
+            Timestamp::from_unix_timestamp(1700000000).unwrap()
+        );
+        assert_eq!(
+            parse_since("2023-11-14T23:13:20+01:00").unwrap(),
+            Timestamp::from_unix_timestamp(1700000000).unwrap()
+        );
+    }
+
+    #[test]
+    fn since_duration() {
+        let before = Timestamp::now().unix_timestamp();
+        let since = parse_since("90m").unwrap().unix_timestamp();
+        let after = Timestamp::now().unix_timestamp();
+        assert!((before - 90 * 60..=after - 90 * 60).contains(&since));
+
+        let since = parse_since("2d").unwrap().unix_timestamp();
+        assert!(Timestamp::now().unix_timestamp() - since >= 2 * 24 * 60 * 60);
+
+        assert!(parse_since("90").is_err());
+        assert!(parse_since("90y").is_err());
+        assert!(parse_since("m").is_err());
+        assert!(parse_since("").is_err());
+    }
+}
@@ -1,104 +1,310 @@
+use crate::config::WiperData;
+use crate::planner::DeletionPlan;
 use anyhow::Result;
-use chrono::Days;
 use futures::{Stream, StreamExt};
-use poise::serenity_prelude::{Message, Timestamp};
+use poise::serenity_prelude::{
+    self as serenity, ChannelId, HttpError, Message, MessageId, Timestamp, UserId,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::time::Duration;
-use utils::reporter::{CountingReporter, Reporter, SimpleReporter};
+use tokio::time::sleep;
+use utils::reporter::{format_duration, CountingReporter, Reporter};
 
+/// Messages linked in the final status, per reason
+const LISTED_MESSAGES: usize = 5;
+/// Retries of failed deletions, after the first attempt
+const RETRY_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for every next one
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// Discord error code of deleting a message that no longer exists
+const UNKNOWN_MESSAGE: isize = 10008;
+
+/// ID and last edit time of archived messages
+pub type ArchivedMessages = FxHashMap<MessageId, Option<Timestamp>>;
+
+/// Messages wipes and purges never delete, even when they are in range
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PreservationRules {
+    /// Keep pinned messages
+    pub pinned: bool,
+    /// Keep messages of these users or bots
+    pub authors: Vec<UserId>,
+    /// Keep these messages
+    pub messages: Vec<MessageId>,
+}
+
+impl PreservationRules {
+    pub fn preserves(&self, message: &Message) -> bool {
+        (self.pinned && message.pinned)
+            || self.authors.contains(&message.author.id)
+            || self.messages.contains(&message.id)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WipeOptions {
+    /// Number of messages the stream holds, when an earlier pass over it
+    /// counted them
+    pub expected: Option<usize>,
+    /// Only delete messages as they were archived. Messages missing from the
+    /// archive, or edited since, are kept
+    pub archived: Option<ArchivedMessages>,
+    pub preserve: PreservationRules,
+}
+
+/// Outcome of a wipe, shown as its final status
+#[derive(Debug, Clone, Default)]
+pub struct WipeSummary {
+    pub deleted: usize,
+    pub elapsed: Duration,
+    /// Links to messages kept by the preservation rules
+    pub preserved: Vec<String>,
+    /// Links to messages kept because the archive doesn't contain them
+    pub not_archived: Vec<String>,
+    /// Links to messages kept because they were edited after archival
+    pub edited: Vec<String>,
+    /// Links to messages that couldn't be deleted, even after retries
+    pub failed: Vec<String>,
+    /// Last error of a failed deletion
+    pub last_error: Option<String>,
+    /// Links to deleted messages the verification scan still found
+    pub leftover: Vec<String>,
+    /// Error that stopped the verification scan
+    pub verification_error: Option<String>,
+}
+
+impl WipeSummary {
+    /// Whether every planned message is confirmed to be gone
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty() && self.leftover.is_empty() && self.verification_error.is_none()
+    }
+}
+
+fn write_links(f: &mut Formatter<'_>, links: &[String], description: &str) -> std::fmt::Result {
+    if links.is_empty() {
+        return Ok(());
+    }
+    write!(
+        f,
+        "\n{}: {}",
+        description.replace(
+            "{}",
+            &pluralizer::pluralize("messages", links.len() as isize, true)
+        ),
+        links[..links.len().min(LISTED_MESSAGES)].join(" ")
+    )?;
+    if links.len() > LISTED_MESSAGES {
+        write!(f, " and {} more", links.len() - LISTED_MESSAGES)?;
+    }
+    Ok(())
+}
+
+impl Display for WipeSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Wiping {}: {} deleted in {}",
+            match self.is_complete() {
+                true => "finished",
+                false => "incomplete",
+            },
+            pluralizer::pluralize("messages", self.deleted as isize, true),
+            format_duration(self.elapsed)
+        )?;
+        write_links(f, &self.preserved, "Preserved {}")?;
+        write_links(f, &self.not_archived, "Kept {} that aren't in the archive")?;
+        write_links(f, &self.edited, "Kept {} edited after they were archived")?;
+        write_links(f, &self.failed, "Could not delete {}")?;
+        if let Some(error) = &self.last_error {
+            write!(f, "\nLast error: {error}")?;
+        }
+        write_links(f, &self.leftover, "{} still exist after wiping")?;
+        if let Some(error) = &self.verification_error {
+            write!(f, "\nCould not verify the wipe: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Deletes the messages of the stream, within the limits of `options`.
+/// `messages` is called again to verify that the deleted messages are gone
 pub async fn wipe_messages<
     Messages: Stream<Item = Result<Message>> + Send,
+    MessagesFn: Fn() -> Messages,
     Reporter: Fn(String, bool) -> ReportResult,
     ReportResult: Future<Output = Result<()>>,
-    Data: Send + Sync,
+    Data: WiperData,
 >(
     ctx: poise::Context<'_, Data, anyhow::Error>,
-    messages: Messages,
+    messages: MessagesFn,
+    options: WipeOptions,
     report: Reporter,
-) -> Result<()> {
-    let two_weeks_ago = Timestamp::from(
-        Timestamp::now()
-            .checked_sub_days(Days::new(13))
-            .expect("Invalid system clock time"),
-    );
+) -> Result<WipeSummary> {
+    let mut reporter = CountingReporter::new_with_defaults(Duration::from_secs(5), 100, report);
+    reporter.total = options.expected;
 
-    let mut reporter = SimpleReporter::new(Duration::from_secs(1), report);
-
-    reporter.report("Fetching messages".to_string()).await?;
-
-    let mut messages = messages.boxed();
+    reporter
+        .force_report("Fetching messages".to_string())
+        .await?;
 
-    let mut initial_bulk = vec![];
-    let mut next_message = None;
-    while let Some(message) = messages.next().await {
+    let mut stream = messages().boxed();
+    let mut planned = vec![];
+    let mut summary = WipeSummary::default();
+    while let Some(message) = stream.next().await {
         let message = message?;
-        if message.timestamp > two_weeks_ago {
-            initial_bulk.push(message);
-        } else {
-            next_message = Some(message);
-            break;
+        match options.archived.as_ref().map(|e| e.get(&message.id)) {
+            _ if options.preserve.preserves(&message) => summary.preserved.push(message.link()),
+            Some(None) => summary.not_archived.push(message.link()),
+            Some(Some(edited)) if *edited != message.edited_timestamp => {
+                summary.edited.push(message.link())
+            }
+            _ => planned.push((message.channel_id, message.id, message.timestamp)),
         }
-    }
 
-    reporter
-        .report("Deleting recent messages".to_string())
-        .await?;
-
-    for messages in initial_bulk.chunks(100) {
-        ctx.channel_id().delete_messages(ctx, messages).await?;
+        reporter.current_count += 1;
+        reporter
+            .report(format!("Fetching messages: {}", reporter.progress()))
+            .await?;
     }
 
-    reporter.report("Deleting old messages".to_string()).await?;
-
-    let mut reporter = CountingReporter::new_with_defaults(
-        Duration::from_secs(5),
-        20,
+    execute_plan(
+        ctx,
+        DeletionPlan::new(planned),
+        summary,
+        messages,
         reporter.into_report_function(),
-    );
+    )
+    .await
+}
 
-    reporter.current_count = initial_bulk.len();
-    reporter.last_count = initial_bulk.len();
+fn is_unknown_message(error: &serenity::Error) -> bool {
+    matches!(
+        error,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if response.error.code == UNKNOWN_MESSAGE
+    )
+}
 
-    if let Some(message) = next_message {
-        message.delete(ctx).await?;
-        reporter.current_count += 1;
+/// Deletes the planned messages, retrying failed deletions with backoff,
+/// then scans `messages` for any that are left
+pub async fn execute_plan<
+    Messages: Stream<Item = Result<Message>> + Send,
+    MessagesFn: Fn() -> Messages,
+    Reporter: Fn(String, bool) -> ReportResult,
+    ReportResult: Future<Output = Result<()>>,
+    Data: WiperData,
+>(
+    ctx: poise::Context<'_, Data, anyhow::Error>,
+    plan: DeletionPlan,
+    mut summary: WipeSummary,
+    messages: MessagesFn,
+    report: Reporter,
+) -> Result<WipeSummary> {
+    let mut reporter = CountingReporter::new_with_defaults(Duration::from_secs(5), 20, report)
+        .with_total(plan.len());
+    let concurrency = ctx.data().wiper_config().delete_concurrency.max(1);
 
-        // let mut bulk = vec![];
-        while let Some(message) = messages.next().await {
-            let message = message?;
-            message.delete(ctx).await?;
+    reporter
+        .force_report(format!("Deleting messages: {}", reporter.progress()))
+        .await?;
 
-            reporter.current_count += 1;
+    let mut planned = FxHashSet::default();
+    let mut pending: Vec<(ChannelId, MessageId)> = vec![];
+    for bulk in &plan.bulk {
+        planned.extend(bulk.messages.iter().copied());
+        match bulk.channel.delete_messages(ctx, &bulk.messages).await {
+            Ok(()) => reporter.current_count += bulk.messages.len(),
+            // Retried one by one, which also works for messages that grew
+            // too old for bulk deletion in the meantime
+            Err(_) => pending.extend(bulk.messages.iter().map(|id| (bulk.channel, *id))),
+        }
+        reporter
+            .report(format!("Deleting messages: {}", reporter.progress()))
+            .await?;
+    }
+    planned.extend(plan.single.iter().map(|(_, id)| *id));
+    pending.extend(plan.single);
+
+    let mut failed = vec![];
+    for attempt in 0..=RETRY_ATTEMPTS {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            let delay = RETRY_BACKOFF * 2u32.pow(attempt - 1);
             reporter
-                .report(format!(
-                    "Deleting messages: {}/unknown",
-                    reporter.current_count
+                .force_report(format!(
+                    "Retrying {} failed {} in {}",
+                    pending.len(),
+                    pluralizer::pluralize("deletions", pending.len() as isize, false),
+                    format_duration(delay)
                 ))
                 .await?;
-            // bulk.push(channel_id.delete_message(ctx, message_id));
-            // if bulk.len() > BATCH_SIZE {
-            //     futures::future::join_all(bulk).await;
-            //     bulk = vec![];
-            //     reporter.current_count += BATCH_SIZE;
-            //     reporter
-            //         .report(format!(
-            //             "Deleting messages: {}/unknown",
-            //             reporter.current_count
-            //         ))
-            //         .await?;
-            // }
+            sleep(delay).await;
+        }
+
+        failed.clear();
+        let mut deletions = futures::stream::iter(std::mem::take(&mut pending))
+            .map(|(channel, message)| async move {
+                let result = channel.delete_message(ctx, message).await;
+                (channel, message, result)
+            })
+            .buffer_unordered(concurrency);
+        while let Some((channel, message, result)) = deletions.next().await {
+            match result {
+                // Already gone, which is what we want
+                Ok(()) => reporter.current_count += 1,
+                Err(error) if is_unknown_message(&error) => reporter.current_count += 1,
+                Err(error) => {
+                    pending.push((channel, message));
+                    failed.push((channel, message, error));
+                }
+            }
+            reporter
+                .report(format!("Deleting messages: {}", reporter.progress()))
+                .await?;
         }
-        // reporter.current_count += bulk.len();
-        // reporter
-        //     .report(format!(
-        //         "Wiping messages: {}/unknown",
-        //         reporter.current_count
-        //     ))
-        //     .await?;
-        // futures::future::join_all(bulk).await;
     }
 
-    reporter.force_report("Wiping finished".to_string()).await?;
+    let guild_id = ctx.guild_id();
+    summary.last_error = failed.last().map(|(_, _, error)| error.to_string());
+    summary.failed = failed
+        .iter()
+        .map(|(channel, message, _)| message.link(*channel, guild_id))
+        .collect();
 
-    Ok(())
+    reporter
+        .force_report("Verifying the wipe".to_string())
+        .await?;
+    for (_, message, _) in &failed {
+        planned.remove(message);
+    }
+    // Messages come newest first, nothing planned is older than the oldest
+    if let Some(oldest) = planned.iter().min().copied() {
+        let mut stream = messages().boxed().take_while(|message: &Result<Message>| {
+            futures::future::ready(message.as_ref().map_or(true, |e| e.id >= oldest))
+        });
+        while let Some(message) = stream.next().await {
+            match message {
+                Ok(message) if planned.contains(&message.id) => {
+                    summary.leftover.push(message.link())
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    summary.verification_error = Some(format!("{error:#}"));
+                    break;
+                }
+            }
+        }
+    }
+
+    summary.deleted = reporter.current_count;
+    summary.elapsed = reporter.elapsed();
+    reporter.force_report(summary.to_string()).await?;
+
+    Ok(summary)
 }